@@ -0,0 +1,16 @@
+use color_eyre::Result;
+use distributed_systems_challenge::challenges::broadcast;
+use distributed_systems_challenge::topology::TopologyStrategy;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // e.g. `BROADCAST_TOPOLOGY=tree:4`, see `TopologyStrategy::from_str`
+    let strategy = match std::env::var("BROADCAST_TOPOLOGY") {
+        Ok(strategy) => strategy.parse()?,
+        Err(_) => TopologyStrategy::default(),
+    };
+
+    broadcast::server(strategy)
+        .serve()
+        .await
+}
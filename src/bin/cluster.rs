@@ -0,0 +1,537 @@
+use std::collections::{HashMap, HashSet};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use color_eyre::eyre::{bail, eyre, OptionExt};
+use color_eyre::Result;
+use distributed_systems_challenge::{Message, NodeId, ServiceEmulator};
+use distributed_systems_challenge::checker::{self, Checker};
+use distributed_systems_challenge::history::History;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::process::{Child, Command};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, WeakUnboundedSender};
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+const USAGE: &str = "Usage: cluster <challenge binary> [--nodes N] [--listen ADDR] [--check WORKLOAD]";
+
+/// Requests typed on stdin are sent from this client, and replies to it are printed to stdout
+const STDIN_CLIENT_ID: &str = "c1";
+
+/// How long requests from stdin still get to be answered once stdin is closed
+const REPLY_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// How long the nodes get to exit once their stdin is closed, before they are killed
+const NODE_EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A local stand-in for Maelstrom: runs a challenge binary as a cluster of nodes, routes their messages to each
+/// other, emulates the Maelstrom services and lets clients send requests from stdin or over TCP.
+///
+/// Requests on stdin are either whole messages or `<dest> <body>`, e.g. `n0 {"type": "echo", "echo": "hi"}`.
+/// TCP clients send whole messages, one per line, and get replies on the same connection.
+///
+/// With `--check`, the requests of all clients and the replies to them are recorded and checked against the
+/// workload's guarantees once the cluster has shut down, e.g. `--check broadcast`.
+#[tokio::main]
+async fn main() -> Result<()> {
+    color_eyre::install()?;
+
+    let args = Args::parse()?;
+
+    let node_ids = (0..args.nodes)
+        .map(|index| format!("n{index}"))
+        .collect::<Vec<_>>();
+
+    let (router_tx, router_rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut nodes = HashMap::new();
+    let mut children = Vec::new();
+
+    for node_id in &node_ids {
+        let mut child = Command::new(&args.binary)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|err| eyre!("Failed to start {}: {err}", args.binary))?;
+
+        let stdin = child.stdin.take().ok_or_eyre("Missing child stdin")?;
+        let stdout = child.stdout.take().ok_or_eyre("Missing child stdout")?;
+        let stderr = child.stderr.take().ok_or_eyre("Missing child stderr")?;
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(write_lines(stdin, rx));
+        tokio::spawn(forward_lines(stdout, router_tx.clone()));
+
+        let prefix = node_id.clone();
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(stderr).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                eprintln!("[{prefix}] {line}");
+            }
+        });
+
+        let init = json!({
+            "src": "c0",
+            "dest": node_id,
+            "body": {
+                "type": "init",
+                "msg_id": 0,
+                "node_id": node_id,
+                "node_ids": node_ids,
+            },
+        });
+        tx.send(init.to_string())?;
+
+        nodes.insert(node_id.clone(), tx);
+        children.push((node_id.clone(), child));
+    }
+
+    let clients = Arc::new(Mutex::new(HashMap::new()));
+
+    if let Some(address) = &args.listen {
+        let listener = TcpListener::bind(address).await?;
+        eprintln!("Listening for clients on {}", listener.local_addr()?);
+
+        tokio::spawn(accept_clients(listener, router_tx.downgrade(), clients.clone()));
+    }
+
+    let history = args.checker.as_ref().map(|_| History::new());
+    let pending = Arc::new(Mutex::new(HashSet::new()));
+    let (close_tx, close_rx) = oneshot::channel();
+
+    let (stdout_tx, stdout_rx) = tokio::sync::mpsc::unbounded_channel();
+    let printer = tokio::spawn(write_lines(tokio::io::stdout(), stdout_rx));
+
+    let stdin_client = StdinClient {
+        pending: pending.clone(),
+        output: stdout_tx,
+    };
+
+    // The router and the TCP clients only hold on to its sender weakly, so that it stops once every node has closed
+    // its stdout
+    let mut router = tokio::spawn(route(router_rx, router_tx.downgrade(), nodes, clients, stdin_client, history.clone(), close_rx));
+
+    // Stdin closing shuts the cluster down, unless clients are connecting over TCP
+    read_stdin(router_tx, pending.clone()).await?;
+
+    if args.listen.is_some() {
+        tokio::signal::ctrl_c().await?;
+    }
+
+    let deadline = Instant::now() + REPLY_GRACE_PERIOD;
+    while !pending.lock().unwrap().is_empty() && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    // Closing the nodes' stdin makes them shut down on their own
+    let _ = close_tx.send(());
+
+    let deadline = Instant::now() + NODE_EXIT_TIMEOUT;
+    for (node_id, mut child) in children {
+        wait_or_kill(&node_id, &mut child, deadline).await?;
+    }
+
+    // The nodes' last messages only need a moment
+    if tokio::time::timeout(Duration::from_secs(1), &mut router).await.is_err() {
+        router.abort();
+    }
+
+    // The printer stops once the router has let go of it, after writing out what is left
+    let _ = printer.await;
+
+    if let (Some(checker), Some(history)) = (args.checker, history) {
+        let report = history.check(&*checker);
+        eprintln!("{report}");
+
+        if !report.valid {
+            bail!("History check failed");
+        }
+    }
+
+    Ok(())
+}
+
+struct Args {
+    binary: String,
+    nodes: usize,
+    listen: Option<String>,
+    checker: Option<Box<dyn Checker>>,
+}
+
+impl Args {
+    fn parse() -> Result<Self> {
+        let mut args = std::env::args().skip(1);
+        let mut binary = None;
+        let mut nodes = 3;
+        let mut listen = None;
+        let mut checker = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--nodes" => nodes = args.next().ok_or_eyre(USAGE)?.parse()?,
+                "--listen" => listen = Some(args.next().ok_or_eyre(USAGE)?),
+                "--check" => {
+                    let workload = args.next().ok_or_eyre(USAGE)?;
+                    checker = Some(checker::for_workload(&workload).ok_or_else(|| eyre!("No checker for workload {workload:?}"))?);
+                },
+                "--help" | "-h" => bail!(USAGE),
+                _ if binary.is_none() => binary = Some(arg),
+                _ => bail!("Unexpected argument {arg:?}\n{USAGE}"),
+            }
+        }
+
+        Ok(Self {
+            binary: binary.ok_or_eyre(USAGE)?,
+            nodes,
+            listen,
+            checker,
+        })
+    }
+}
+
+/// Whoever is typing on stdin: the requests still waiting for a reply and where the replies are printed
+struct StdinClient {
+    pending: Arc<Mutex<HashSet<i64>>>,
+    output: UnboundedSender<String>,
+}
+
+async fn route(
+    mut rx: UnboundedReceiver<String>,
+    tx: WeakUnboundedSender<String>,
+    mut nodes: HashMap<NodeId, UnboundedSender<String>>,
+    clients: Arc<Mutex<HashMap<NodeId, UnboundedSender<String>>>>,
+    stdin_client: StdinClient,
+    history: Option<History>,
+    mut close_rx: oneshot::Receiver<()>,
+) {
+    let services = ServiceEmulator::new();
+
+    // Once the nodes are told to stop, messages between them that are still in flight are dropped rather than
+    // mistaken for replies to the stdin client
+    let node_ids = nodes.keys().cloned().collect::<HashSet<_>>();
+
+    loop {
+        let line = tokio::select! {
+            line = rx.recv() => match line {
+                Some(line) => line,
+                None => break,
+            },
+            _ = &mut close_rx, if !close_rx.is_terminated() => {
+                nodes.clear();
+                continue;
+            },
+        };
+
+        let message = match serde_json::from_str::<Value>(&line) {
+            Ok(message) => message,
+            Err(err) => {
+                eprintln!("Dropping malformed message {line:?}: {err}");
+                continue;
+            },
+        };
+
+        let src = message["src"].as_str().unwrap_or_default();
+        let dest = message["dest"].as_str().unwrap_or_default();
+
+        if let Some(history) = &history {
+            if node_ids.contains(dest) && !node_ids.contains(src) && !services.handles(src) {
+                history.record_request(&message);
+            } else if !node_ids.contains(dest) && !services.handles(dest) && dest != "c0" {
+                history.record_reply(&message);
+            }
+        }
+
+        if node_ids.contains(dest) {
+            if let Some(node) = nodes.get(dest) {
+                let _ = node.send(line);
+            }
+        } else if services.handles(dest) {
+            let Ok(request) = serde_json::from_value::<Message<Value>>(message) else {
+                eprintln!("Dropping malformed request {line:?}");
+                continue;
+            };
+
+            if let (Some(reply), Some(tx)) = (services.handle(request).await, tx.upgrade()) {
+                let _ = tx.send(serde_json::to_string(&reply).unwrap_or_default());
+            }
+        } else if let Some(client) = clients.lock().unwrap().get(dest) {
+            let _ = client.send(line);
+        } else if dest != "c0" {
+            // Everything else, except the replies to `init`, is for whoever is typing on stdin
+            if let Some(in_reply_to) = message["body"]["in_reply_to"].as_i64() {
+                stdin_client.pending.lock().unwrap().remove(&in_reply_to);
+            }
+
+            let _ = stdin_client.output.send(line);
+        }
+    }
+}
+
+async fn read_stdin(tx: UnboundedSender<String>, pending: Arc<Mutex<HashSet<i64>>>) -> Result<()> {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut message_id = 1;
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match parse_request(&line, message_id) {
+            Ok(request) => {
+                message_id += 1;
+
+                if request["src"] == STDIN_CLIENT_ID {
+                    if let Some(request_id) = request["body"]["msg_id"].as_i64() {
+                        pending.lock().unwrap().insert(request_id);
+                    }
+                }
+
+                tx.send(request.to_string())?;
+            },
+            Err(err) => eprintln!("{err}"),
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_request(line: &str, message_id: i32) -> Result<Value> {
+    let line = line.trim();
+
+    let mut request = if line.starts_with('{') {
+        serde_json::from_str::<Value>(line)?
+    } else {
+        let (dest, body) = line.split_once(char::is_whitespace)
+            .ok_or_eyre("Expected a message or `<dest> <body>`")?;
+
+        json!({
+            "src": STDIN_CLIENT_ID,
+            "dest": dest,
+            "body": serde_json::from_str::<Value>(body.trim())?,
+        })
+    };
+
+    if request["body"]["msg_id"].is_null() {
+        request["body"]["msg_id"] = json!(message_id);
+    }
+
+    Ok(request)
+}
+
+/// Waits for a node to exit, killing it if it is still running at `deadline`
+async fn wait_or_kill(node_id: &str, child: &mut Child, deadline: Instant) -> Result<()> {
+    if tokio::time::timeout_at(deadline, child.wait()).await.is_ok() {
+        return Ok(());
+    }
+
+    eprintln!("{node_id} did not exit in time, killing it");
+    child.kill().await?;
+    Ok(())
+}
+
+async fn accept_clients(listener: TcpListener, tx: WeakUnboundedSender<String>, clients: Arc<Mutex<HashMap<NodeId, UnboundedSender<String>>>>) {
+    while let Ok((stream, _)) = listener.accept().await {
+        let (read, write) = stream.into_split();
+        let (reply_tx, reply_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(write_lines(write, reply_rx));
+
+        let tx = tx.clone();
+        let clients = clients.clone();
+
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(read).lines();
+
+            while let Ok(Some(line)) = lines.next_line().await {
+                // Replies to a client go back over the connection its requests came in on
+                if let Some(src) = serde_json::from_str::<Value>(&line).ok().and_then(|message| message["src"].as_str().map(str::to_string)) {
+                    clients.lock().unwrap().insert(src, reply_tx.clone());
+                }
+
+                // Only upgraded for the send, so that connected clients don't keep the router going
+                if tx.upgrade().is_none_or(|tx| tx.send(line).is_err()) {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+async fn write_lines(write: impl AsyncWrite + Unpin, mut rx: UnboundedReceiver<String>) -> Result<()> {
+    let mut write = tokio::io::BufWriter::new(write);
+    let mut batch = Vec::new();
+
+    while rx.recv_many(&mut batch, 256).await > 0 {
+        for line in batch.drain(..) {
+            write.write_all(line.as_bytes()).await?;
+            write.write_all(b"\n").await?;
+        }
+
+        write.flush().await?;
+    }
+
+    Ok(())
+}
+
+async fn forward_lines(read: impl AsyncRead + Unpin, tx: UnboundedSender<String>) -> Result<()> {
+    let mut lines = BufReader::new(read).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        tx.send(line)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_whole_messages_and_dest_body_pairs() {
+        let request = parse_request(r#"  n1 {"type": "echo", "echo": "hi"} "#, 7).unwrap();
+        assert_eq!(request, json!({ "src": STDIN_CLIENT_ID, "dest": "n1", "body": { "type": "echo", "echo": "hi", "msg_id": 7 } }));
+
+        let request = parse_request(r#"{"src": "c2", "dest": "n0", "body": {"type": "read", "msg_id": 3}}"#, 7).unwrap();
+        assert_eq!(request, json!({ "src": "c2", "dest": "n0", "body": { "type": "read", "msg_id": 3 } }));
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        assert!(parse_request("n0", 1).is_err());
+        assert!(parse_request("n0 {not json}", 1).is_err());
+        assert!(parse_request("{\"src\": ", 1).is_err());
+    }
+
+    struct Cluster {
+        tx: UnboundedSender<String>,
+        nodes: HashMap<NodeId, UnboundedReceiver<String>>,
+        stdout: UnboundedReceiver<String>,
+        pending: Arc<Mutex<HashSet<i64>>>,
+        clients: Arc<Mutex<HashMap<NodeId, UnboundedSender<String>>>>,
+        close_tx: Option<oneshot::Sender<()>>,
+        router: tokio::task::JoinHandle<()>,
+    }
+
+    impl Cluster {
+        fn start(node_ids: &[&str]) -> Self {
+            let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+            let (stdout_tx, stdout) = tokio::sync::mpsc::unbounded_channel();
+            let (close_tx, close_rx) = oneshot::channel();
+            let pending = Arc::new(Mutex::new(HashSet::new()));
+            let clients = Arc::new(Mutex::new(HashMap::new()));
+
+            let (senders, nodes) = node_ids.iter()
+                .map(|node_id| {
+                    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+                    ((node_id.to_string(), tx), (node_id.to_string(), rx))
+                })
+                .unzip();
+
+            let stdin_client = StdinClient {
+                pending: pending.clone(),
+                output: stdout_tx,
+            };
+
+            let router = tokio::spawn(route(rx, tx.downgrade(), senders, clients.clone(), stdin_client, None, close_rx));
+
+            Self { tx, nodes, stdout, pending, clients, close_tx: Some(close_tx), router }
+        }
+
+        fn send(&self, message: Value) {
+            self.tx.send(message.to_string()).unwrap();
+        }
+
+        async fn stop(self) -> UnboundedReceiver<String> {
+            drop(self.tx);
+            self.router.await.unwrap();
+            self.stdout
+        }
+    }
+
+    async fn recv(rx: &mut UnboundedReceiver<String>) -> Value {
+        serde_json::from_str(&rx.recv().await.unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn routes_between_nodes_services_and_clients() {
+        let mut cluster = Cluster::start(&["n0", "n1"]);
+        cluster.pending.lock().unwrap().insert(1);
+
+        let (client_tx, mut client_rx) = tokio::sync::mpsc::unbounded_channel();
+        cluster.clients.lock().unwrap().insert("c2".to_string(), client_tx);
+
+        cluster.send(json!({ "src": "c1", "dest": "n0", "body": { "type": "echo", "msg_id": 1 } }));
+        assert_eq!(recv(cluster.nodes.get_mut("n0").unwrap()).await["body"]["type"], "echo");
+
+        cluster.send(json!({ "src": "n0", "dest": "n1", "body": { "type": "sync", "msg_id": 1 } }));
+        assert_eq!(recv(cluster.nodes.get_mut("n1").unwrap()).await["body"]["type"], "sync");
+
+        cluster.send(json!({ "src": "n1", "dest": "seq-kv", "body": { "type": "write", "key": "a", "value": "1", "msg_id": 2 } }));
+        let reply = recv(cluster.nodes.get_mut("n1").unwrap()).await;
+        assert_eq!(reply["src"], "seq-kv");
+        assert_eq!(reply["body"]["type"], "write_ok");
+
+        cluster.send(json!({ "src": "n0", "dest": "c2", "body": { "type": "read_ok", "in_reply_to": 5 } }));
+        assert_eq!(recv(&mut client_rx).await["body"]["in_reply_to"], 5);
+
+        cluster.send(json!({ "src": "n0", "dest": "c1", "body": { "type": "echo_ok", "in_reply_to": 1 } }));
+        cluster.send(json!({ "src": "n0", "dest": "c0", "body": { "type": "init_ok", "in_reply_to": 0 } }));
+
+        let pending = cluster.pending.clone();
+        let mut stdout = cluster.stop().await;
+
+        assert_eq!(recv(&mut stdout).await["body"]["type"], "echo_ok");
+        assert!(stdout.try_recv().is_err());
+        assert!(pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn connected_clients_do_not_keep_the_router_going() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(accept_clients(listener, tx.downgrade(), Arc::new(Mutex::new(HashMap::new()))));
+
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        stream.write_all(b"{\"src\": \"c2\", \"dest\": \"n0\", \"body\": {\"type\": \"read\"}}\n").await.unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&rx.recv().await.unwrap()).unwrap()["src"], "c2");
+
+        drop(tx);
+        assert!(rx.recv().await.is_none());
+        drop(stream);
+    }
+
+    #[tokio::test]
+    async fn kills_nodes_that_do_not_exit_in_time() {
+        let mut child = Command::new("sleep").arg("60").kill_on_drop(true).spawn().unwrap();
+
+        let start = Instant::now();
+        wait_or_kill("n0", &mut child, start + Duration::from_millis(100)).await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let status = child.try_wait().unwrap().expect("still running");
+        assert!(!status.success());
+    }
+
+    #[tokio::test]
+    async fn drops_messages_between_nodes_once_they_are_closing() {
+        let mut cluster = Cluster::start(&["n0", "n1"]);
+
+        let _ = cluster.close_tx.take().unwrap().send(());
+        tokio::task::yield_now().await;
+
+        cluster.send(json!({ "src": "n0", "dest": "n1", "body": { "type": "sync", "msg_id": 4 } }));
+        cluster.send(json!({ "src": "n1", "dest": "c1", "body": { "type": "read_ok", "in_reply_to": 2 } }));
+
+        let mut stdout = cluster.stop().await;
+
+        // Only the late reply to the stdin client is printed
+        assert_eq!(recv(&mut stdout).await["body"]["type"], "read_ok");
+        assert!(stdout.try_recv().is_err());
+    }
+}
@@ -0,0 +1,9 @@
+use color_eyre::Result;
+use distributed_systems_challenge::challenges::echo;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    echo::server()
+        .serve()
+        .await
+}
@@ -0,0 +1,9 @@
+use color_eyre::Result;
+use distributed_systems_challenge::challenges::grow_only_counter;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    grow_only_counter::server()
+        .serve()
+        .await
+}
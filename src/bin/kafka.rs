@@ -0,0 +1,9 @@
+use color_eyre::Result;
+use distributed_systems_challenge::challenges::kafka;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    kafka::server()
+        .serve()
+        .await
+}
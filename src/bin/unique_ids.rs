@@ -0,0 +1,9 @@
+use color_eyre::Result;
+use distributed_systems_challenge::challenges::unique_ids;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    unique_ids::server()
+        .serve()
+        .await
+}
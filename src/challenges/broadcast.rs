@@ -1,132 +1,617 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, PoisonError};
 use std::time::Duration;
 
-use color_eyre::eyre::{OptionExt, Result};
+use color_eyre::eyre::Result;
 use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tokio::task::JoinSet;
+use tokio::time::{Instant, MissedTickBehavior};
 
-use distributed_systems_challenge::{Message, MessageReply, Node, NodeId, NodeServer};
-
-#[tokio::main]
-async fn main() -> Result<()> {
-    NodeServer::new(State::default(), message_handler)
-        .add_task(sync_with_neighbours, Duration::from_millis(750))
-        .serve()
-        .await
-}
-
-async fn message_handler(node: Node<State, Payload>, message: Message<Payload>) -> MessageReply<Payload> {
-    let (message, payload) = message.take_payload();
-
-    Ok(match payload {
-        Payload::Broadcast { broadcast_message } => {
-            node.state.write().unwrap().broadcast_messages.insert(broadcast_message);
-            Some(message.into_reply(Payload::BroadcastOk))
-        },
-        Payload::Read => {
-            Some(message.into_reply(Payload::ReadOk {
-                broadcast_messages: node.state.read().unwrap().broadcast_messages.clone(),
-            }))
-        },
-        Payload::Topology { .. } => {
-            let mut state = node.state.write().unwrap();
-            // let neighbours = topology.remove(&node.node_id).ok_or_eyre("Topology missing node of self")?;
-            //
-            // for neighbour in neighbours {
-            //     state.neighbours.insert(
-            //         neighbour,
-            //         HashSet::new(),
-            //     );
-            // }
-
-            // All node communicate with all other nodes for low latency
-            for node in node.node_ids.clone() {
-                state.neighbours.insert(node, HashSet::new());
-            }
+use crate::{Node, NodeId, NodeServer, Reply, RetryPolicy, Router, RpcOptions};
+use crate::extract::{NodeIdentity, Payload, Src, State};
+use crate::topology::TopologyStrategy;
+
+/// A neighbour's batch is sent as soon as it holds this many fresh messages, and never holds more
+const MAX_BATCH_SIZE: usize = 64;
+
+/// How often neighbours are checked for batches that are due or messages that need resending
+const FLUSH_CHECK_PERIOD: Duration = Duration::from_millis(10);
+
+const MIN_FLUSH_INTERVAL: Duration = Duration::from_millis(20);
+const MAX_FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+
+const MIN_RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(100);
+const MAX_RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Assumed until a neighbour's first acknowledgement arrives
+const INITIAL_ACK_LATENCY: Duration = Duration::from_millis(200);
+
+/// Gossips with the neighbours `strategy` picks
+pub fn server(strategy: TopologyStrategy) -> NodeServer<BroadcastState> {
+    let router = Router::new()
+        .route("broadcast", broadcast)
+        .route("read", read)
+        .route("topology", topology)
+        .route("sync", sync);
+
+    let state = BroadcastState {
+        strategy,
+        ..BroadcastState::default()
+    };
+
+    NodeServer::new(state, router)
+        .add_background_task(sync_with_neighbours)
+}
+
+async fn broadcast(state: State<BroadcastState>, Payload(Broadcast { broadcast_message }): Payload<Broadcast>) -> Reply<BroadcastOk> {
+    state.write().unwrap().receive([broadcast_message], None);
 
-            Some(message.into_reply(Payload::TopologyOk))
-        },
-        Payload::Sync { new_messages } => {
-            let mut state = node.state.write().unwrap();
-            let src = message.src.clone();
-
-            state.neighbours.get_mut(&src)
-                .ok_or_eyre("Unknown neighbour")?
-                .extend(new_messages.clone());
-            state.broadcast_messages.extend(new_messages.clone());
-
-            let reply = message.into_reply(Payload::SyncOk { acknowledge_new_messages: new_messages });
-
-            Some(reply)
-        },
-        Payload::SyncOk { acknowledge_new_messages } => {
-            let mut state = node.state.write().unwrap();
-
-            state.neighbours.get_mut(&message.src)
-                .ok_or_eyre("Unknown neighbour")?
-                .extend(acknowledge_new_messages);
-
-            None
-        },
-        Payload::BroadcastOk | Payload::ReadOk { .. } | Payload::TopologyOk => {
-            None
-        },
+    Reply(BroadcastOk {})
+}
+
+async fn read(state: State<BroadcastState>) -> Reply<ReadOk> {
+    Reply(ReadOk {
+        broadcast_messages: state.read().unwrap().broadcast_messages.clone(),
     })
 }
 
-async fn sync_with_neighbours(node: Node<State, Payload>) -> Result<()> {
-    let messages = {
-        let state = node.state.read().unwrap();
+async fn topology(state: State<BroadcastState>, node: NodeIdentity, Payload(Topology { topology }): Payload<Topology>) -> Reply<TopologyOk> {
+    let mut state = state.write().unwrap();
 
-        state.neighbours.iter()
-            .map(|(neighbour_id, messages_known)| {
-                let messages_to_send = state.broadcast_messages.difference(&messages_known)
-                    .copied()
-                    .collect::<HashSet<_>>();
+    let neighbours = state.strategy.neighbours(&node.node_id, &node.node_ids, &topology);
+    state.set_neighbours(neighbours, Instant::now());
 
-                (neighbour_id.clone(), messages_to_send)
-            })
-            .filter(|(_, messages_to_send)| !messages_to_send.is_empty())
-            .collect::<Vec<_>>()
-    };
+    Reply(TopologyOk {})
+}
+
+async fn sync(state: State<BroadcastState>, Src(src): Src, Payload(SyncMessages { new_messages }): Payload<SyncMessages>) -> Reply<SyncOk> {
+    state.write().unwrap().receive(new_messages.iter().copied(), Some(&src));
+
+    Reply(SyncOk { acknowledge_new_messages: new_messages })
+}
+
+/// A background task that runs until the node shuts down, sending the batches that are due every `FLUSH_CHECK_PERIOD`, and as soon as
+/// messages arrive so they don't sit in a queue until the next check at low load. Each flush runs on its own, so a
+/// slow or partitioned neighbour doesn't hold up the others, but in a `JoinSet` of this task: none outlive the
+/// node, and a panicking flush takes the task down with it, to be handled like any other task failure.
+async fn sync_with_neighbours(node: Node<BroadcastState>) -> Result<()> {
+    let arrived = node.state.read().unwrap().arrived.clone();
+    let mut flushes = JoinSet::new();
+
+    let mut interval = tokio::time::interval(FLUSH_CHECK_PERIOD);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    while !node.is_shutting_down() {
+        tokio::select! {
+            biased;
+            Some(result) = flushes.join_next() => {
+                if let Err(err) = result {
+                    if err.is_panic() {
+                        std::panic::resume_unwind(err.into_panic());
+                    }
+                }
+
+                continue;
+            },
+            _ = arrived.notified() => {},
+            _ = interval.tick() => {},
+        }
 
-    for (dest, messages_to_send) in messages {
-        node.send_new_message(dest, Payload::Sync {
-            new_messages: messages_to_send,
-        }).await?;
+        let now = Instant::now();
+
+        let due = node.state.read().unwrap().neighbours.iter()
+            .filter(|(_, neighbour)| neighbour.is_due(now))
+            .map(|(dest, _)| dest.clone())
+            .collect::<Vec<_>>();
+
+        for dest in due {
+            flushes.spawn(flush(node.clone(), dest));
+        }
     }
 
     Ok(())
 }
 
-type MessagesKnown = HashSet<i32>;
+async fn flush(node: Node<BroadcastState>, dest: NodeId) {
+    let (batch, timeout) = {
+        let mut state = node.state.write().unwrap();
+
+        let Some(neighbour) = state.neighbours.get_mut(&dest) else {
+            return;
+        };
+
+        match neighbour.take_batch(Instant::now()) {
+            Some(batch) => (batch, neighbour.retransmit_timeout()),
+            None => return,
+        }
+    };
+
+    let _syncing = Syncing {
+        node: &node,
+        dest: &dest,
+    };
+
+    // Unacknowledged messages are resent once their retransmit timeout passes, so each sync is only attempted once
+    let options = RpcOptions::default()
+        .timeout(timeout)
+        .retry(RetryPolicy::none());
+
+    let sent_at = Instant::now();
+    let reply = node.rpc_with_options(dest.clone(), SyncMessages { new_messages: batch }, &options).await;
+
+    let mut state = node.state.write().unwrap();
+
+    if let Some(neighbour) = state.neighbours.get_mut(&dest) {
+        match reply {
+            Ok(SyncOk { acknowledge_new_messages }) => neighbour.acknowledge(acknowledge_new_messages, sent_at.elapsed()),
+            Err(_) => neighbour.back_off(),
+        }
+    }
+}
+
+/// Lets the next batch to `dest` go out once the flush of the current one ends, however it ends: a flush that
+/// panics or is aborted mustn't leave the neighbour waiting for an acknowledgement forever
+struct Syncing<'a> {
+    node: &'a Node<BroadcastState>,
+    dest: &'a NodeId,
+}
+
+impl Drop for Syncing<'_> {
+    fn drop(&mut self) {
+        let mut state = self.node.state.write().unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(neighbour) = state.neighbours.get_mut(self.dest) {
+            neighbour.syncing = false;
+        }
+    }
+}
 
 #[derive(Default)]
-struct State {
-    neighbours: HashMap<NodeId, MessagesKnown>,
-    broadcast_messages: HashSet<i32>,
+pub struct BroadcastState {
+    strategy: TopologyStrategy,
+    neighbours: BTreeMap<NodeId, Neighbour>,
+    broadcast_messages: BTreeSet<i32>,
+    /// Wakes `sync_with_neighbours` when there are new messages to pass on
+    arrived: Arc<Notify>,
+}
+
+impl BroadcastState {
+    /// Neighbours that stay keep their batches and whatever they have in flight, so a repeated `topology` message
+    /// changes nothing. Messages received before the topology still have to reach the new neighbours.
+    fn set_neighbours(&mut self, neighbours: Vec<NodeId>, now: Instant) {
+        self.neighbours.retain(|neighbour_id, _| neighbours.contains(neighbour_id));
+
+        for neighbour_id in neighbours {
+            self.neighbours.entry(neighbour_id)
+                .or_insert_with(|| Neighbour::new(self.broadcast_messages.iter().copied().collect(), now));
+        }
+    }
+
+    /// Queues messages seen for the first time for every neighbour except the one they came from
+    fn receive(&mut self, messages: impl IntoIterator<Item = i32>, src: Option<&NodeId>) {
+        let messages = messages.into_iter().collect::<Vec<_>>();
+
+        if let Some(neighbour) = src.and_then(|src| self.neighbours.get_mut(src)) {
+            neighbour.forget(&messages);
+        }
+
+        for message in messages {
+            if !self.broadcast_messages.insert(message) {
+                continue;
+            }
+
+            for (_, neighbour) in self.neighbours.iter_mut().filter(|(neighbour_id, _)| Some(*neighbour_id) != src) {
+                neighbour.fresh.push(message);
+            }
+
+            self.arrived.notify_one();
+        }
+    }
+}
+
+/// What still has to reach a neighbour
+struct Neighbour {
+    /// Messages not sent to the neighbour yet, oldest first
+    fresh: Vec<i32>,
+    /// Messages sent but not acknowledged yet, and when they were last sent
+    in_flight: BTreeMap<i32, Instant>,
+    last_flush: Instant,
+    /// Whether a batch is on its way and hasn't been acknowledged or timed out yet
+    syncing: bool,
+    /// Smoothed round trip time of syncs
+    ack_latency: Duration,
+}
+
+impl Neighbour {
+    fn new(fresh: Vec<i32>, now: Instant) -> Self {
+        Self {
+            fresh,
+            in_flight: BTreeMap::new(),
+            last_flush: now,
+            syncing: false,
+            ack_latency: INITIAL_ACK_LATENCY,
+        }
+    }
+
+    /// At most one batch per round trip: messages go out straight away at low load, while at high load they pile
+    /// up into batches, and the slower the neighbour answers the bigger those get
+    fn flush_interval(&self) -> Duration {
+        self.ack_latency.clamp(MIN_FLUSH_INTERVAL, MAX_FLUSH_INTERVAL)
+    }
+
+    fn retransmit_timeout(&self) -> Duration {
+        (self.ack_latency * 2).clamp(MIN_RETRANSMIT_TIMEOUT, MAX_RETRANSMIT_TIMEOUT)
+    }
+
+    fn is_due(&self, now: Instant) -> bool {
+        if self.syncing {
+            return false;
+        }
+
+        let fresh_due = !self.fresh.is_empty()
+            && (self.fresh.len() >= MAX_BATCH_SIZE || now >= self.last_flush + self.flush_interval());
+
+        let timeout = self.retransmit_timeout();
+        fresh_due || self.in_flight.values().any(|sent_at| now >= *sent_at + timeout)
+    }
+
+    /// Fresh messages come first, and resent ones fill whatever room they leave
+    fn take_batch(&mut self, now: Instant) -> Option<BTreeSet<i32>> {
+        if !self.is_due(now) {
+            return None;
+        }
+
+        let mut batch = self.fresh.drain(..self.fresh.len().min(MAX_BATCH_SIZE)).collect::<BTreeSet<_>>();
+
+        let timeout = self.retransmit_timeout();
+        let stale = self.in_flight.iter()
+            .filter(|(_, sent_at)| now >= **sent_at + timeout)
+            .map(|(message, _)| *message)
+            .take(MAX_BATCH_SIZE.saturating_sub(batch.len()))
+            .collect::<Vec<_>>();
+
+        batch.extend(stale);
+
+        for message in &batch {
+            self.in_flight.insert(*message, now);
+        }
+
+        self.last_flush = now;
+        self.syncing = true;
+        Some(batch)
+    }
+
+    fn acknowledge(&mut self, messages: BTreeSet<i32>, latency: Duration) {
+        for message in messages {
+            self.in_flight.remove(&message);
+        }
+
+        self.ack_latency = (self.ack_latency * 7 + latency) / 8;
+        self.syncing = false;
+    }
+
+    /// A sync went unanswered, so the neighbour or the link is slower than thought
+    fn back_off(&mut self) {
+        self.ack_latency = (self.ack_latency * 2).min(MAX_RETRANSMIT_TIMEOUT);
+        self.syncing = false;
+    }
+
+    /// Drops messages the neighbour is known to have already, e.g. because it sent them to us
+    fn forget(&mut self, messages: &[i32]) {
+        self.fresh.retain(|message| !messages.contains(message));
+
+        for message in messages {
+            self.in_flight.remove(message);
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "broadcast")]
+struct Broadcast {
+    #[serde(rename = "message")]
+    broadcast_message: i32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "broadcast_ok")]
+struct BroadcastOk {}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "read_ok")]
+struct ReadOk {
+    #[serde(rename = "messages")]
+    broadcast_messages: BTreeSet<i32>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "topology")]
+struct Topology {
+    topology: HashMap<NodeId, Vec<NodeId>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "topology_ok")]
+struct TopologyOk {}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "sync")]
+struct SyncMessages {
+    new_messages: BTreeSet<i32>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
-enum Payload {
-    Broadcast {
-        #[serde(rename = "message")]
-        broadcast_message: i32,
-    },
-    BroadcastOk,
-    Read,
-    ReadOk {
-        #[serde(rename = "messages")]
-        broadcast_messages: HashSet<i32>,
-    },
-    Topology {
-        topology: HashMap<NodeId, Vec<NodeId>>,
-    },
-    TopologyOk,
-    Sync {
-        new_messages: HashSet<i32>,
-    },
-    SyncOk {
-        acknowledge_new_messages: HashSet<i32>,
-    },
+#[serde(tag = "type", rename = "sync_ok")]
+struct SyncOk {
+    acknowledge_new_messages: BTreeSet<i32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::simulator::{LinkFaults, NetworkConfig, Partition, Simulator};
+    use crate::topology::grid;
+    use crate::workload::{Workload, WorkloadConfig};
+    use super::*;
+
+    async fn start(node_count: usize, config: NetworkConfig, strategy: TopologyStrategy) -> Simulator {
+        let simulator = Simulator::start(node_count, config, || server(strategy)).await.unwrap();
+        let topology = grid(simulator.node_ids());
+
+        for node_id in simulator.node_ids() {
+            let _: TopologyOk = simulator.request(node_id, json!({ "type": "topology", "topology": topology })).await.unwrap();
+        }
+
+        simulator
+    }
+
+    /// Three nodes with n0 in the middle, so n1 only hears about messages from n0
+    async fn start_star(seed: u64) -> Simulator {
+        let simulator = Simulator::start(3, NetworkConfig::default().seed(seed), || server(TopologyStrategy::Provided)).await.unwrap();
+        let topology = json!({ "n0": ["n1", "n2"], "n1": ["n0"], "n2": ["n0"] });
+
+        for node_id in simulator.node_ids() {
+            let _: TopologyOk = simulator.request(node_id, json!({ "type": "topology", "topology": topology })).await.unwrap();
+        }
+
+        simulator
+    }
+
+    async fn broadcast_to(simulator: &Simulator, node_id: &str, message: i32) {
+        let _: BroadcastOk = simulator.request(node_id, json!({ "type": "broadcast", "message": message })).await.unwrap();
+    }
+
+    /// The nodes that have every one of `messages`
+    async fn nodes_with_all(simulator: &Simulator, messages: &BTreeSet<i32>) -> usize {
+        let mut nodes = 0;
+
+        for node_id in simulator.node_ids() {
+            let ReadOk { broadcast_messages } = simulator.request(node_id, json!({ "type": "read" })).await.unwrap();
+            nodes += usize::from(broadcast_messages.is_superset(messages));
+        }
+
+        nodes
+    }
+
+    #[test]
+    fn one_batch_at_a_time() {
+        let start = Instant::now();
+        let mut neighbour = Neighbour::new((0..MAX_BATCH_SIZE as i32).collect(), start);
+
+        let batch = neighbour.take_batch(start).unwrap();
+        neighbour.fresh.extend(100..100 + MAX_BATCH_SIZE as i32);
+
+        // Full batch or not, nothing more is sent until the first is acknowledged
+        assert!(neighbour.take_batch(start + MAX_FLUSH_INTERVAL).is_none());
+
+        neighbour.acknowledge(batch, Duration::from_millis(10));
+        assert!(neighbour.take_batch(start + MAX_FLUSH_INTERVAL).is_some());
+    }
+
+    #[test]
+    fn a_repeated_topology_keeps_what_neighbours_have_in_flight() {
+        let now = Instant::now();
+        let mut state = BroadcastState::default();
+        state.set_neighbours(vec!["n1".to_string(), "n2".to_string()], now);
+
+        state.receive([1, 2], None);
+        let batch = state.neighbours.get_mut("n1").unwrap().take_batch(now + MAX_FLUSH_INTERVAL).unwrap();
+        assert_eq!(batch, BTreeSet::from([1, 2]));
+
+        state.set_neighbours(vec!["n1".to_string(), "n3".to_string()], now);
+
+        let n1 = &state.neighbours["n1"];
+        assert!(n1.syncing);
+        assert_eq!(n1.in_flight.keys().copied().collect::<Vec<_>>(), [1, 2]);
+
+        // A new neighbour catches up on everything, and one that's gone is forgotten
+        assert_eq!(state.neighbours["n3"].fresh, [1, 2]);
+        assert!(!state.neighbours.contains_key("n2"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn every_node_receives_every_message() {
+        for strategy in [TopologyStrategy::Provided, TopologyStrategy::KaryTree { arity: 2 }, TopologyStrategy::Ring] {
+            let simulator = start(9, NetworkConfig::default().seed(1), strategy).await;
+            let messages = (0..20).collect::<BTreeSet<_>>();
+
+            for message in &messages {
+                let node_id = &simulator.node_ids()[*message as usize % 9];
+                broadcast_to(&simulator, node_id, *message).await;
+            }
+
+            tokio::time::sleep(Duration::from_secs(2)).await;
+
+            assert_eq!(nodes_with_all(&simulator, &messages).await, 9, "{strategy:?} didn't converge");
+            simulator.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn resends_messages_over_lossy_links() {
+        let faults = LinkFaults::default()
+            .latency(Duration::from_millis(20)..Duration::from_millis(100))
+            .drop_probability(0.3)
+            .duplicate_probability(0.1)
+            .reorder_probability(0.1);
+
+        let simulator = start(9, NetworkConfig::default().seed(2).faults(faults), TopologyStrategy::default()).await;
+        let messages = (0..20).collect::<BTreeSet<_>>();
+
+        for message in &messages {
+            broadcast_to(&simulator, "n0", *message).await;
+        }
+
+        tokio::time::sleep(Duration::from_secs(10)).await;
+
+        assert_eq!(nodes_with_all(&simulator, &messages).await, 9);
+        simulator.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn an_unreachable_neighbour_does_not_hold_up_the_others() {
+        let simulator = start_star(5).await;
+        simulator.set_link_faults("n0", "n1", LinkFaults::default().drop_probability(1.0));
+
+        let messages = (0..20).collect::<BTreeSet<_>>();
+
+        for message in &messages {
+            broadcast_to(&simulator, "n0", *message).await;
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let ReadOk { broadcast_messages } = simulator.request("n2", json!({ "type": "read" })).await.unwrap();
+        assert_eq!(broadcast_messages, messages);
+
+        // Once the link works again, the neighbour that was cut off gets its batches too
+        simulator.set_link_faults("n0", "n1", LinkFaults::default());
+        tokio::time::sleep(Duration::from_secs(5)).await;
+
+        let ReadOk { broadcast_messages } = simulator.request("n1", json!({ "type": "read" })).await.unwrap();
+        assert_eq!(broadcast_messages, messages);
+
+        simulator.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_slow_neighbour_does_not_hold_up_the_others() {
+        let simulator = start_star(7).await;
+        simulator.set_link_faults("n0", "n1", LinkFaults::default().latency(Duration::from_millis(400)));
+
+        let messages = (0..20).collect::<BTreeSet<_>>();
+
+        for message in &messages {
+            broadcast_to(&simulator, "n0", *message).await;
+            tokio::time::sleep(Duration::from_millis(25)).await;
+        }
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let ReadOk { broadcast_messages } = simulator.request("n2", json!({ "type": "read" })).await.unwrap();
+        assert_eq!(broadcast_messages, messages);
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+
+        let ReadOk { broadcast_messages } = simulator.request("n1", json!({ "type": "read" })).await.unwrap();
+        assert_eq!(broadcast_messages, messages);
+
+        simulator.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn catches_up_once_a_partition_heals() {
+        let simulator = start(6, NetworkConfig::default().seed(3), TopologyStrategy::FullyConnected).await;
+        let messages = (0..10).collect::<BTreeSet<_>>();
+
+        simulator.partition(Partition::Halves);
+
+        for message in &messages {
+            broadcast_to(&simulator, "n0", *message).await;
+        }
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(nodes_with_all(&simulator, &messages).await < 6, "messages crossed the partition");
+
+        simulator.heal();
+        tokio::time::sleep(Duration::from_secs(10)).await;
+
+        assert_eq!(nodes_with_all(&simulator, &messages).await, 6);
+        simulator.shutdown().await.unwrap();
+    }
+
+    /// A whole workload over faulty links, giving back the history and the stats
+    async fn run_workload(seed: u64) -> (serde_json::Value, serde_json::Value) {
+        let faults = LinkFaults::default()
+            .latency(Duration::from_millis(5)..Duration::from_millis(50))
+            .drop_probability(0.1)
+            .duplicate_probability(0.1)
+            .reorder_probability(0.1);
+
+        let simulator = Simulator::start(5, NetworkConfig::default().seed(seed).faults(faults), || server(TopologyStrategy::default())).await.unwrap();
+        let config = WorkloadConfig::default()
+            .seed(seed)
+            .rate(50.0)
+            .duration(Duration::from_secs(3));
+
+        Workload::Broadcast { topology: None }.run(&simulator, &config).await.unwrap();
+
+        let history = serde_json::to_value(simulator.history().operations()).unwrap();
+        let stats = serde_json::to_value(simulator.stats()).unwrap();
+
+        simulator.shutdown().await.unwrap();
+        (history, stats)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_seed_always_plays_out_the_same_way() {
+        let (history, stats) = run_workload(6).await;
+
+        for _ in 0..2 {
+            let (replayed_history, replayed_stats) = run_workload(6).await;
+
+            assert_eq!(replayed_history, history);
+            assert_eq!(replayed_stats, stats);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn passes_the_broadcast_checker() {
+        let workload = Workload::Broadcast { topology: None };
+        let simulator = Simulator::start(5, NetworkConfig::default().seed(4), || server(TopologyStrategy::default())).await.unwrap();
+
+        let config = WorkloadConfig::default()
+            .seed(4)
+            .rate(50.0)
+            .duration(Duration::from_secs(5));
+
+        workload.run(&simulator, &config).await.unwrap();
+
+        let report = simulator.history().check(workload.checker().unwrap().as_ref());
+        assert!(report.valid, "{report}");
+
+        simulator.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stays_under_the_efficiency_target() {
+        let faults = LinkFaults::default().latency(Duration::from_millis(100));
+        let simulator = Simulator::start(25, NetworkConfig::default().seed(7).faults(faults), || server(TopologyStrategy::default())).await.unwrap();
+
+        let config = WorkloadConfig::default()
+            .seed(7)
+            .rate(100.0)
+            .concurrency(25)
+            .duration(Duration::from_secs(10));
+
+        Workload::Broadcast { topology: None }.run(&simulator, &config).await.unwrap();
+
+        // The challenge's target for 25 nodes with 100ms of latency
+        let stats = simulator.stats();
+        assert!(stats.msgs_per_op < 20.0, "{} msgs/op", stats.msgs_per_op);
+
+        simulator.shutdown().await.unwrap();
+    }
 }
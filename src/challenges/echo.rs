@@ -1,34 +1,26 @@
 use serde::{Deserialize, Serialize};
-use color_eyre::Result;
-use distributed_systems_challenge::{Message, MessageReply, Node, NodeServer};
+use crate::{NodeServer, Reply, Router};
+use crate::extract::Payload;
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    NodeServer::new((), message_handler)
-        .serve()
-        .await
+pub fn server() -> NodeServer<()> {
+    let router = Router::new()
+        .route("echo", echo);
+
+    NodeServer::new((), router)
 }
 
-async fn message_handler(_node: Node<(), Payload>, message: Message<Payload>) -> MessageReply<Payload> {
-    let (message, payload) = message.take_payload();
+async fn echo(Payload(Echo { echo }): Payload<Echo>) -> Reply<EchoOk> {
+    Reply(EchoOk { echo })
+}
 
-    Ok(match payload {
-        Payload::Echo { echo } => {
-            Some(message.into_reply(Payload::EchoOk { echo }))
-        },
-        Payload::EchoOk { .. } => {
-            None
-        },
-    })
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "echo")]
+struct Echo {
+    echo: String,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
-enum Payload {
-    Echo {
-        echo: String,
-    },
-    EchoOk {
-        echo: String,
-    },
+#[serde(tag = "type", rename = "echo_ok")]
+struct EchoOk {
+    echo: String,
 }
@@ -1,57 +1,100 @@
 use color_eyre::Result;
 use futures::future::join_all;
 use serde::{Deserialize, Serialize};
-use distributed_systems_challenge::{Message, MessageReply, Node, NodeServer};
+use crate::{KeyValueStore, NodeId, NodeServer, Reply, Router};
+use crate::extract::{KvClient, NodeIdentity, Payload};
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    NodeServer::new((), message_handler)
-        .serve()
-        .await
+pub fn server() -> NodeServer<()> {
+    let router = Router::new()
+        .route("add", handle_add)
+        .route("read", handle_read);
+
+    NodeServer::new((), router)
+}
+
+async fn handle_add(node: NodeIdentity, kv: KvClient, Payload(Add { delta }): Payload<Add>) -> Result<Reply<AddOk>> {
+    add(&*kv, &node.node_id, delta).await?;
+    Ok(Reply(AddOk {}))
+}
+
+async fn handle_read(node: NodeIdentity, kv: KvClient) -> Reply<ReadOk> {
+    let value = read(&*kv, &node.node_ids).await;
+    Reply(ReadOk { value })
 }
 
-async fn message_handler(node: Node<(), Payload>, message: Message<Payload>) -> MessageReply<Payload> {
-    let (message, payload) = message.take_payload();
+async fn add(kv: &impl KeyValueStore, node_id: &NodeId, delta: i32) -> Result<()> {
+    if delta != 0 {
+        kv.compare_and_swap_loop(node_id.clone(), |value: Option<&i32>| value.copied().unwrap_or(0) + delta).await?;
+    }
 
-    Ok(match payload {
-        Payload::Add { delta } => {
-            if delta != 0 {
-                let value = node.seq_kv.read(node.node_id.clone()).await.unwrap_or(0);
-                node.seq_kv.write(node.node_id.clone(), value + delta).await?;
-            }
+    Ok(())
+}
 
-            Some(message.into_reply(Payload::AddOk))
-        },
-        Payload::Read => {
-            let mut sum = 0;
+async fn read(kv: &impl KeyValueStore, node_ids: &[NodeId]) -> i32 {
+    let mut sum = 0;
 
-            // Retry read multiple times to probabilistically ensure that we retrieve the most recent value
-            for _ in 0..10 {
-                let counters = node.node_ids.clone().into_iter()
-                    .map(|id| node.seq_kv.read::<i32>(id));
+    // Retry read multiple times to probabilistically ensure that we retrieve the most recent value
+    for _ in 0..10 {
+        let counters = node_ids.iter()
+            .map(|id| kv.read::<i32>(id.clone()));
 
-                sum = join_all(counters).await.into_iter()
-                    .map(|counter| counter.unwrap_or(0))
-                    .sum();
-            }
+        sum = join_all(counters).await.into_iter()
+            .map(|counter| counter.unwrap_or(0))
+            .sum();
+    }
 
-            Some(message.into_reply(Payload::ReadOk { value: sum }))
-        },
-        Payload::AddOk | Payload::ReadOk { .. } => {
-            None
-        },
-    })
+    sum
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
-enum Payload {
-    Add {
-        delta: i32,
-    },
-    AddOk,
-    Read,
-    ReadOk {
-        value: i32,
-    },
+#[serde(tag = "type", rename = "add")]
+struct Add {
+    delta: i32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "add_ok")]
+struct AddOk {}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "read_ok")]
+struct ReadOk {
+    value: i32,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Consistency, MemoryKVStore};
+    use super::*;
+
+    #[tokio::test]
+    async fn read_sums_the_adds_of_every_node() {
+        let kv = MemoryKVStore::seeded(Consistency::Linearizable, 1);
+        let node_ids = ["n0", "n1", "n2"].map(String::from);
+
+        let adds = node_ids.iter().enumerate().flat_map(|(index, node_id)| {
+            // Each node talks to the store through its own session
+            let kv = kv.clone();
+            (1..=10).map(move |delta| {
+                let kv = kv.clone();
+                async move { add(&kv, node_id, delta * (index as i32 + 1)).await }
+            })
+        });
+
+        for result in join_all(adds).await {
+            result.unwrap();
+        }
+
+        assert_eq!(read(&kv, &node_ids).await, 55 * (1 + 2 + 3));
+    }
+
+    #[tokio::test]
+    async fn adding_zero_writes_nothing() {
+        let kv = MemoryKVStore::seeded(Consistency::Sequential, 1);
+
+        add(&kv, &"n0".to_string(), 0).await.unwrap();
+
+        assert!(kv.read::<i32>("n0".to_string()).await.is_err());
+        assert_eq!(read(&kv, &["n0".to_string()]).await, 0);
+    }
 }
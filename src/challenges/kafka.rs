@@ -1,73 +1,74 @@
 use std::collections::HashMap;
-use color_eyre::Result;
 use serde::{Deserialize, Serialize};
-use distributed_systems_challenge::{Message, MessageReply, Node, NodeServer};
+use crate::{NodeServer, Reply, Router};
+use crate::extract::{Payload, State};
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    NodeServer::new(State::default(), message_handler)
-        .serve()
-        .await
+pub fn server() -> NodeServer<KafkaState> {
+    let router = Router::new()
+        .route("send", send)
+        .route("poll", poll)
+        .route("commit_offsets", commit_offsets)
+        .route("list_committed_offsets", list_committed_offsets);
+
+    NodeServer::new(KafkaState::default(), router)
 }
 
-async fn message_handler(node: Node<State, Payload>, message: Message<Payload>) -> MessageReply<Payload> {
-    let (message, payload) = message.take_payload();
-
-    Ok(match payload {
-        Payload::Send { key, log_message } => {
-            let logs = &mut node.state.write().unwrap().logs;
-
-            let log = logs.entry(key).or_default();
-            log.messages.push(log_message);
-
-            Some(message.into_reply(Payload::SendOk { offset: log.messages.len() - 1 }))
-        },
-        Payload::Poll { offsets } => {
-            let logs = &node.state.read().unwrap().logs;
-
-            let log_messages = offsets.into_iter()
-                .map(|(key, offset)| {
-                    let log_messages = logs.get(&key).unwrap().messages.iter()
-                        .copied()
-                        .enumerate()
-                        .skip(offset)
-                        .collect::<Vec<_>>();
-
-                    (key, log_messages)
-                })
-                .collect::<HashMap<_, _>>();
-
-            Some(message.into_reply(Payload::PollOk { log_messages }))
-        },
-        Payload::CommitOffsets { offsets } => {
-            let logs = &mut node.state.write().unwrap().logs;
-
-            for (key, offset) in offsets {
-                logs.get_mut(&key).unwrap().committed_offset = offset;
-            }
-
-            Some(message.into_reply(Payload::CommitOffsetsOk))
-        },
-        Payload::ListCommittedOffsets { keys } => {
-            let logs = &node.state.read().unwrap().logs;
-
-            let offsets = keys.into_iter()
-                .map(|key| {
-                    let offset = logs.get(&key).unwrap().committed_offset;
-                    (key, offset)
-                })
-                .collect::<HashMap<_, _>>();
-
-            Some(message.into_reply(Payload::ListCommittedOffsetsOk { offsets }))
-        },
-        Payload::SendOk { .. } | Payload::PollOk { .. } | Payload::CommitOffsetsOk | Payload::ListCommittedOffsetsOk { .. } => {
-            None
-        },
-    })
+async fn send(state: State<KafkaState>, Payload(SendMessage { key, log_message }): Payload<SendMessage>) -> Reply<SendOk> {
+    let logs = &mut state.write().unwrap().logs;
+
+    let log = logs.entry(key).or_default();
+    log.messages.push(log_message);
+
+    Reply(SendOk { offset: log.messages.len() - 1 })
+}
+
+async fn poll(state: State<KafkaState>, Payload(Poll { offsets }): Payload<Poll>) -> Reply<PollOk> {
+    let logs = &state.read().unwrap().logs;
+
+    // Keys nothing was sent to are left out of the reply, even if offsets were committed for them
+    let log_messages = offsets.into_iter()
+        .filter_map(|(key, offset)| {
+            let log = logs.get(&key).filter(|log| !log.messages.is_empty())?;
+            let log_messages = log.messages.iter()
+                .copied()
+                .enumerate()
+                .skip(offset)
+                .collect::<Vec<_>>();
+
+            Some((key, log_messages))
+        })
+        .collect();
+
+    Reply(PollOk { log_messages })
+}
+
+async fn commit_offsets(state: State<KafkaState>, Payload(CommitOffsets { offsets }): Payload<CommitOffsets>) -> Reply<CommitOffsetsOk> {
+    let logs = &mut state.write().unwrap().logs;
+
+    // Offsets only move forward, so a late or repeated commit can't undo a newer one
+    for (key, offset) in offsets {
+        let log = logs.entry(key).or_default();
+        log.committed_offset = log.committed_offset.max(offset);
+    }
+
+    Reply(CommitOffsetsOk {})
+}
+
+async fn list_committed_offsets(state: State<KafkaState>, Payload(ListCommittedOffsets { keys }): Payload<ListCommittedOffsets>) -> Reply<ListCommittedOffsetsOk> {
+    let logs = &state.read().unwrap().logs;
+
+    let offsets = keys.into_iter()
+        .filter_map(|key| {
+            let offset = logs.get(&key)?.committed_offset;
+            Some((key, offset))
+        })
+        .collect();
+
+    Reply(ListCommittedOffsetsOk { offsets })
 }
 
 #[derive(Default)]
-struct State {
+pub struct KafkaState {
     logs: HashMap<String, Log>,
 }
 
@@ -78,31 +79,154 @@ struct Log {
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
-enum Payload {
-    Send {
-        key: String,
-        #[serde(rename = "msg")]
-        log_message: i32,
-    },
-    SendOk {
-        offset: usize,
-    },
-    Poll {
-        offsets: HashMap<String, usize>,
-    },
-    PollOk {
-        #[serde(rename = "msgs")]
-        log_messages: HashMap<String, Vec<(usize, i32)>>
-    },
-    CommitOffsets {
-        offsets: HashMap<String, usize>,
-    },
-    CommitOffsetsOk,
-    ListCommittedOffsets {
-        keys: Vec<String>,
-    },
-    ListCommittedOffsetsOk {
-        offsets: HashMap<String, usize>,
-    },
+#[serde(tag = "type", rename = "send")]
+struct SendMessage {
+    key: String,
+    #[serde(rename = "msg")]
+    log_message: i32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "send_ok")]
+struct SendOk {
+    offset: usize,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "poll")]
+struct Poll {
+    offsets: HashMap<String, usize>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "poll_ok")]
+struct PollOk {
+    #[serde(rename = "msgs")]
+    log_messages: HashMap<String, Vec<(usize, i32)>>
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "commit_offsets")]
+struct CommitOffsets {
+    offsets: HashMap<String, usize>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "commit_offsets_ok")]
+struct CommitOffsetsOk {}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "list_committed_offsets")]
+struct ListCommittedOffsets {
+    keys: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "list_committed_offsets_ok")]
+struct ListCommittedOffsetsOk {
+    offsets: HashMap<String, usize>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+    use crate::simulator::{NetworkConfig, Simulator};
+    use crate::workload::{KeyDistribution, Workload, WorkloadConfig};
+    use super::*;
+
+    async fn send_to(simulator: &Simulator, key: &str, message: i32) -> usize {
+        let SendOk { offset } = simulator.request("n0", json!({ "type": "send", "key": key, "msg": message })).await.unwrap();
+        offset
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_get_increasing_offsets_per_key() {
+        let simulator = Simulator::start(1, NetworkConfig::default(), server).await.unwrap();
+
+        assert_eq!(send_to(&simulator, "a", 10).await, 0);
+        assert_eq!(send_to(&simulator, "a", 11).await, 1);
+        assert_eq!(send_to(&simulator, "b", 20).await, 0);
+        assert_eq!(send_to(&simulator, "a", 12).await, 2);
+
+        let PollOk { log_messages } = simulator.request("n0", json!({ "type": "poll", "offsets": { "a": 1, "b": 0 } })).await.unwrap();
+
+        assert_eq!(log_messages["a"], vec![(1, 11), (2, 12)]);
+        assert_eq!(log_messages["b"], vec![(0, 20)]);
+
+        simulator.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lists_the_committed_offsets() {
+        let simulator = Simulator::start(1, NetworkConfig::default(), server).await.unwrap();
+
+        send_to(&simulator, "a", 10).await;
+        send_to(&simulator, "b", 20).await;
+
+        let _: CommitOffsetsOk = simulator.request("n0", json!({ "type": "commit_offsets", "offsets": { "a": 1, "b": 1 } })).await.unwrap();
+        let ListCommittedOffsetsOk { offsets } = simulator.request("n0", json!({ "type": "list_committed_offsets", "keys": ["a", "b"] })).await.unwrap();
+
+        assert_eq!(offsets, HashMap::from([("a".to_string(), 1), ("b".to_string(), 1)]));
+
+        simulator.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unknown_keys_are_left_out() {
+        let simulator = Simulator::start(1, NetworkConfig::default(), server).await.unwrap();
+        send_to(&simulator, "a", 10).await;
+
+        let PollOk { log_messages } = simulator.request("n0", json!({ "type": "poll", "offsets": { "a": 0, "b": 0 } })).await.unwrap();
+        assert_eq!(log_messages, HashMap::from([("a".to_string(), vec![(0, 10)])]));
+
+        let ListCommittedOffsetsOk { offsets } = simulator.request("n0", json!({ "type": "list_committed_offsets", "keys": ["a", "b"] })).await.unwrap();
+        assert_eq!(offsets, HashMap::from([("a".to_string(), 0)]));
+
+        // Committing an offset for a key nothing was sent to still records it
+        let _: CommitOffsetsOk = simulator.request("n0", json!({ "type": "commit_offsets", "offsets": { "a": 1, "b": 2 } })).await.unwrap();
+
+        let ListCommittedOffsetsOk { offsets } = simulator.request("n0", json!({ "type": "list_committed_offsets", "keys": ["a", "b", "c"] })).await.unwrap();
+        assert_eq!(offsets, HashMap::from([("a".to_string(), 1), ("b".to_string(), 2)]));
+
+        let PollOk { log_messages } = simulator.request("n0", json!({ "type": "poll", "offsets": { "a": 0, "b": 0 } })).await.unwrap();
+        assert_eq!(log_messages, HashMap::from([("a".to_string(), vec![(0, 10)])]));
+
+        simulator.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn committed_offsets_never_go_back() {
+        let simulator = Simulator::start(1, NetworkConfig::default(), server).await.unwrap();
+        send_to(&simulator, "a", 10).await;
+
+        let _: CommitOffsetsOk = simulator.request("n0", json!({ "type": "commit_offsets", "offsets": { "a": 3 } })).await.unwrap();
+        let _: CommitOffsetsOk = simulator.request("n0", json!({ "type": "commit_offsets", "offsets": { "a": 1 } })).await.unwrap();
+
+        let ListCommittedOffsetsOk { offsets } = simulator.request("n0", json!({ "type": "list_committed_offsets", "keys": ["a"] })).await.unwrap();
+        assert_eq!(offsets, HashMap::from([("a".to_string(), 3)]));
+
+        simulator.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn passes_the_kafka_checker() {
+        let simulator = Simulator::start(1, NetworkConfig::default().seed(5), server).await.unwrap();
+
+        let config = WorkloadConfig::default()
+            .seed(5)
+            .rate(100.0)
+            .concurrency(4)
+            .duration(Duration::from_secs(5))
+            .keys(KeyDistribution::Zipf { keys: 5, exponent: 1.0 });
+
+        Workload::Kafka.run(&simulator, &config).await.unwrap();
+
+        let report = simulator.history().check(Workload::Kafka.checker().unwrap().as_ref());
+        assert!(report.valid, "{report}");
+        assert!(report.operations > 100);
+
+        simulator.shutdown().await.unwrap();
+    }
 }
@@ -0,0 +1,8 @@
+//! The solutions to the Fly.io challenges, each a `NodeServer` ready to be served over stdio by its binary or run in
+//! a `Simulator`
+
+pub mod broadcast;
+pub mod echo;
+pub mod grow_only_counter;
+pub mod kafka;
+pub mod unique_ids;
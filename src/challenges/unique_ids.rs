@@ -1,41 +1,30 @@
 use std::time::SystemTime;
 use serde::{Deserialize, Serialize};
 use color_eyre::Result;
-use distributed_systems_challenge::{Message, MessageReply, Node, NodeServer};
+use crate::{NodeServer, Reply, Router};
+use crate::extract::NodeIdentity;
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    NodeServer::new((), message_handler)
-        .serve()
-        .await
-}
+pub fn server() -> NodeServer<()> {
+    let router = Router::new()
+        .route("generate", generate);
 
-async fn message_handler(node: Node<(), Payload>, message: Message<Payload>) -> MessageReply<Payload> {
-    let (message, payload) = message.take_payload();
+    NodeServer::new((), router)
+}
 
-    Ok(match payload {
-        Payload::Generate => {
-            let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+async fn generate(node: NodeIdentity) -> Result<Reply<GenerateOk>> {
+    let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
 
-            let flake_id = format!(
-                "{}-{}",
-                node.node_id,
-                time.as_nanos(),
-            );
+    let flake_id = format!(
+        "{}-{}",
+        node.node_id,
+        time.as_nanos(),
+    );
 
-            Some(message.into_reply(Payload::GenerateOk { id: flake_id }))
-        },
-        Payload::GenerateOk { .. } => {
-            None
-        },
-    })
+    Ok(Reply(GenerateOk { id: flake_id }))
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
-enum Payload {
-    Generate,
-    GenerateOk {
-        id: String,
-    },
+#[serde(tag = "type", rename = "generate_ok")]
+struct GenerateOk {
+    id: String,
 }
@@ -0,0 +1,701 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+
+use serde::Serialize;
+use serde_json::Value;
+use crate::history::{Operation, Outcome};
+
+/// Checks a history of client operations against what a workload promises
+pub trait Checker: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn check(&self, operations: &[Operation]) -> Vec<Anomaly>;
+
+    fn report(&self, operations: &[Operation]) -> CheckReport {
+        let anomalies = self.check(operations);
+
+        CheckReport {
+            checker: self.name(),
+            valid: anomalies.is_empty(),
+            operations: operations.len(),
+            anomalies,
+        }
+    }
+}
+
+/// Something a history shouldn't contain, along with the operations that show it
+#[derive(Clone, Debug, Serialize)]
+pub struct Anomaly {
+    pub kind: &'static str,
+    pub description: String,
+    pub operations: Vec<Operation>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CheckReport {
+    pub checker: &'static str,
+    pub valid: bool,
+    pub operations: usize,
+    pub anomalies: Vec<Anomaly>,
+}
+
+impl Display for CheckReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.valid {
+            return write!(f, "{}: PASS ({} operations)", self.checker, self.operations);
+        }
+
+        write!(f, "{}: FAIL ({} operations, {} anomalies)", self.checker, self.operations, self.anomalies.len())?;
+
+        for anomaly in &self.anomalies {
+            write!(f, "\n  {}: {}", anomaly.kind, anomaly.description)?;
+
+            for operation in &anomaly.operations {
+                let reply = operation.reply.as_ref().map_or("no reply".to_string(), Value::to_string);
+                write!(f, "\n    #{} {} -> {} {} => {reply}", operation.index, operation.client, operation.node, operation.request)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The checker for a Maelstrom workload name, e.g. `broadcast` or `g-counter`
+pub fn for_workload(workload: &str) -> Option<Box<dyn Checker>> {
+    match workload {
+        "broadcast" => Some(Box::new(SetFull)),
+        "g-counter" => Some(Box::new(CounterBounds)),
+        "kafka" => Some(Box::new(KafkaLog)),
+        "lin-kv" => Some(Box::new(LinearizableRegister)),
+        "txn-rw-register" => Some(Box::new(TxnRegister)),
+        "unique-ids" => Some(Box::new(UniqueIds)),
+        _ => None,
+    }
+}
+
+/// Every generated id must be unique
+pub struct UniqueIds;
+
+impl Checker for UniqueIds {
+    fn name(&self) -> &'static str {
+        "unique-ids"
+    }
+
+    fn check(&self, operations: &[Operation]) -> Vec<Anomaly> {
+        let mut generated = BTreeMap::<String, Vec<&Operation>>::new();
+
+        for operation in operations.iter().filter(|operation| operation.request_type() == "generate") {
+            if let Some(reply) = operation.ok_reply() {
+                generated.entry(reply["id"].to_string()).or_default().push(operation);
+            }
+        }
+
+        generated.into_iter()
+            .filter(|(_, operations)| operations.len() > 1)
+            .map(|(id, operations)| Anomaly {
+                kind: "duplicate-id",
+                description: format!("{id} was generated {} times", operations.len()),
+                operations: operations.into_iter().cloned().collect(),
+            })
+            .collect()
+    }
+}
+
+/// Every acknowledged broadcast must show up in the last read on every node that starts after it, and reads may only
+/// return messages that were broadcast
+pub struct SetFull;
+
+impl Checker for SetFull {
+    fn name(&self) -> &'static str {
+        "set-full"
+    }
+
+    fn check(&self, operations: &[Operation]) -> Vec<Anomaly> {
+        let broadcasts = operations.iter()
+            .filter(|operation| operation.request_type() == "broadcast")
+            .collect::<Vec<_>>();
+
+        let reads = operations.iter()
+            .filter_map(|operation| {
+                let messages = operation.ok_reply()?["messages"].as_array()?;
+                Some((operation, messages.iter().map(Value::to_string).collect::<HashSet<_>>()))
+            })
+            .collect::<Vec<_>>();
+
+        let sent = broadcasts.iter()
+            .map(|operation| operation.request["message"].to_string())
+            .collect::<HashSet<_>>();
+
+        // Only the last read on each node matters, earlier ones may still be waiting for messages to spread
+        let mut last_reads = BTreeMap::<&str, &(&Operation, HashSet<String>)>::new();
+
+        for read in &reads {
+            let last = last_reads.entry(&read.0.node).or_insert(read);
+
+            if read.0.invoked_at > last.0.invoked_at {
+                *last = read;
+            }
+        }
+
+        let mut anomalies = Vec::new();
+
+        for broadcast in broadcasts.iter().filter(|operation| operation.outcome == Outcome::Ok) {
+            let message = broadcast.request["message"].to_string();
+
+            for (read, messages) in last_reads.values().filter(|(read, _)| broadcast.precedes(read)) {
+                if !messages.contains(&message) {
+                    anomalies.push(Anomaly {
+                        kind: "lost",
+                        description: format!("{message} was acknowledged but is missing from the last read on {}", read.node),
+                        operations: vec![(*broadcast).clone(), (*read).clone()],
+                    });
+                }
+            }
+        }
+
+        for (read, messages) in &reads {
+            let mut unexpected = messages.difference(&sent).cloned().collect::<Vec<_>>();
+            unexpected.sort();
+
+            if !unexpected.is_empty() {
+                anomalies.push(Anomaly {
+                    kind: "unexpected",
+                    description: format!("Read messages that were never broadcast: {}", unexpected.join(", ")),
+                    operations: vec![(*read).clone()],
+                });
+            }
+        }
+
+        anomalies
+    }
+}
+
+/// A grow-only counter read must lie between the sum of the adds that finished before it started and the sum of
+/// all adds that might have happened by the time it finished
+pub struct CounterBounds;
+
+impl Checker for CounterBounds {
+    fn name(&self) -> &'static str {
+        "counter-bounds"
+    }
+
+    fn check(&self, operations: &[Operation]) -> Vec<Anomaly> {
+        let adds = operations.iter()
+            .filter(|operation| operation.request_type() == "add" && operation.outcome != Outcome::Fail)
+            .map(|operation| (operation, operation.request["delta"].as_i64().unwrap_or(0)))
+            .collect::<Vec<_>>();
+
+        let mut anomalies = Vec::new();
+
+        for read in operations.iter().filter(|operation| operation.request_type() == "read") {
+            let Some(value) = read.ok_reply().and_then(|reply| reply["value"].as_i64()) else {
+                continue;
+            };
+
+            let lower = adds.iter()
+                .filter(|(add, _)| add.outcome == Outcome::Ok && add.precedes(read))
+                .map(|(_, delta)| delta)
+                .sum::<i64>();
+
+            let upper = adds.iter()
+                .filter(|(add, _)| read.completed_at.is_none_or(|completed_at| add.invoked_at < completed_at))
+                .map(|(_, delta)| delta)
+                .sum::<i64>();
+
+            if value < lower || value > upper {
+                anomalies.push(Anomaly {
+                    kind: "out-of-bounds",
+                    description: format!("Read {value}, but expected between {lower} and {upper}"),
+                    operations: vec![read.clone()],
+                });
+            }
+        }
+
+        anomalies
+    }
+}
+
+/// Kafka-style logs: every acknowledged send gets its own offset, later sends get higher ones, polls return
+/// offsets in order and never skip an acknowledged send, and committed offsets only go backwards when another
+/// client commits an older one
+pub struct KafkaLog;
+
+impl Checker for KafkaLog {
+    fn name(&self) -> &'static str {
+        "kafka"
+    }
+
+    fn check(&self, operations: &[Operation]) -> Vec<Anomaly> {
+        let mut anomalies = Vec::new();
+
+        // Acknowledged sends by key and offset
+        let mut sends = HashMap::<String, BTreeMap<u64, &Operation>>::new();
+
+        for send in operations.iter().filter(|operation| operation.request_type() == "send") {
+            let Some(offset) = send.ok_reply().and_then(|reply| reply["offset"].as_u64()) else {
+                continue;
+            };
+
+            let key = key(&send.request["key"]);
+
+            if let Some(other) = sends.entry(key.clone()).or_default().insert(offset, send) {
+                anomalies.push(Anomaly {
+                    kind: "duplicate-offset",
+                    description: format!("Two sends to {key} were both given offset {offset}"),
+                    operations: vec![other.clone(), send.clone()],
+                });
+            }
+        }
+
+        for (key, sends) in &sends {
+            for (offset, send) in sends {
+                let earlier = sends.range(offset + 1..)
+                    .find(|(_, other)| other.precedes(send));
+
+                if let Some((other_offset, other)) = earlier {
+                    anomalies.push(Anomaly {
+                        kind: "nonmonotonic-send",
+                        description: format!("A send to {key} got offset {offset} after an earlier one got {other_offset}"),
+                        operations: vec![(*other).clone(), (*send).clone()],
+                    });
+                }
+            }
+        }
+
+        for poll in operations.iter().filter(|operation| operation.request_type() == "poll") {
+            let Some(logs) = poll.ok_reply().and_then(|reply| reply["msgs"].as_object()) else {
+                continue;
+            };
+
+            for (key, entries) in logs {
+                let entries = entries.as_array().into_iter().flatten()
+                    .filter_map(|entry| Some((entry[0].as_u64()?, &entry[1])))
+                    .collect::<Vec<_>>();
+
+                if entries.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+                    anomalies.push(Anomaly {
+                        kind: "nonmonotonic-poll",
+                        description: format!("Polled offsets of {key} out of order"),
+                        operations: vec![poll.clone()],
+                    });
+                }
+
+                let Some(sends) = sends.get(key) else {
+                    continue;
+                };
+
+                for (offset, message) in &entries {
+                    if let Some(send) = sends.get(offset).filter(|send| send.request["msg"] != **message) {
+                        anomalies.push(Anomaly {
+                            kind: "inconsistent-offset",
+                            description: format!("Polled {message} at offset {offset} of {key}, but {} was sent there", send.request["msg"]),
+                            operations: vec![(*send).clone(), poll.clone()],
+                        });
+                    }
+                }
+
+                // Sends that finished before the poll started and fall within the polled range must all be there
+                let (Some(from), Some((last, _))) = (poll.request["offsets"][key].as_u64(), entries.last()) else {
+                    continue;
+                };
+
+                let polled = entries.iter().map(|(offset, _)| *offset).collect::<HashSet<_>>();
+
+                for (offset, send) in sends.range(from..*last) {
+                    if send.precedes(poll) && !polled.contains(offset) {
+                        anomalies.push(Anomaly {
+                            kind: "lost-write",
+                            description: format!("Poll of {key} from {from} skipped acknowledged offset {offset}"),
+                            operations: vec![(*send).clone(), poll.clone()],
+                        });
+                    }
+                }
+            }
+        }
+
+        let commits = operations.iter()
+            .filter(|operation| operation.request_type() == "commit_offsets" && operation.outcome != Outcome::Fail)
+            .collect::<Vec<_>>();
+
+        for list in operations.iter().filter(|operation| operation.request_type() == "list_committed_offsets") {
+            let Some(offsets) = list.ok_reply().and_then(|reply| reply["offsets"].as_object()) else {
+                continue;
+            };
+
+            let keys = list.request["keys"].as_array().into_iter().flatten()
+                .map(key)
+                .collect::<HashSet<_>>();
+
+            for commit in commits.iter().filter(|commit| commit.outcome == Outcome::Ok && commit.precedes(list)) {
+                for (key, committed) in commit.request["offsets"].as_object().into_iter().flatten().filter(|(key, _)| keys.contains(*key)) {
+                    let (Some(committed), listed) = (committed.as_u64(), offsets.get(key).and_then(Value::as_u64)) else {
+                        continue;
+                    };
+
+                    // Clients commit independently, so a lower offset is fine if another commit could have set it since
+                    let overwritten = listed.is_some_and(|listed| commits.iter().any(|other| {
+                        other.request["offsets"][key].as_u64() == Some(listed) && !other.precedes(commit) && !list.precedes(other)
+                    }));
+
+                    if listed.is_none_or(|listed| listed < committed) && !overwritten {
+                        anomalies.push(Anomaly {
+                            kind: "committed-offset-regression",
+                            description: format!("Listed {listed:?} as the committed offset of {key} after {committed} was committed"),
+                            operations: vec![(*commit).clone(), list.clone()],
+                        });
+                    }
+                }
+            }
+        }
+
+        anomalies
+    }
+}
+
+/// Reads of a `lin-kv` register may only return a value some write or cas put there, and not one that was already
+/// overwritten by a write that finished before the read started. This catches stale reads, not every violation of
+/// linearizability.
+pub struct LinearizableRegister;
+
+impl Checker for LinearizableRegister {
+    fn name(&self) -> &'static str {
+        "lin-kv"
+    }
+
+    fn check(&self, operations: &[Operation]) -> Vec<Anomaly> {
+        // Writes and swaps that may have taken effect, with their key and the value they left behind
+        let writes = operations.iter()
+            .filter(|operation| operation.outcome != Outcome::Fail)
+            .filter_map(|operation| {
+                let value = match operation.request_type() {
+                    "write" => &operation.request["value"],
+                    "cas" => &operation.request["to"],
+                    _ => return None,
+                };
+
+                Some((operation, key(&operation.request["key"]), value))
+            })
+            .collect::<Vec<_>>();
+
+        let mut anomalies = Vec::new();
+
+        for read in operations.iter().filter(|operation| operation.request_type() == "read") {
+            let Some(value) = read.ok_reply().map(|reply| &reply["value"]) else {
+                continue;
+            };
+
+            let key = key(&read.request["key"]);
+            let writes = writes.iter().filter(|(_, other, _)| *other == key).collect::<Vec<_>>();
+            let sources = writes.iter().filter(|(_, _, written)| *written == value).collect::<Vec<_>>();
+
+            if sources.is_empty() {
+                anomalies.push(Anomaly {
+                    kind: "unexpected",
+                    description: format!("Read {value} from {key}, which was never written"),
+                    operations: vec![read.clone()],
+                });
+
+                continue;
+            }
+
+            // The read is stale if every write of its value was followed by an acknowledged write of another one
+            let overwritten = sources.iter()
+                .map(|(source, _, _)| writes.iter().find(|(other, _, written)| {
+                    other.outcome == Outcome::Ok && *written != value && source.precedes(other) && other.precedes(read)
+                }))
+                .collect::<Option<Vec<_>>>();
+
+            if let Some(overwritten) = overwritten {
+                let (latest, _, written) = overwritten.iter().max_by_key(|(other, _, _)| other.completed_at).unwrap();
+
+                anomalies.push(Anomaly {
+                    kind: "stale-read",
+                    description: format!("Read {value} from {key} after {written} was written over it"),
+                    operations: vec![(*latest).clone(), read.clone()],
+                });
+            }
+        }
+
+        anomalies
+    }
+}
+
+/// Transactions over read-write registers may only read values written by transactions that didn't fail, and must
+/// see their own earlier writes. Values are expected to be written at most once, as `Workload::TxnRwRegister` does.
+pub struct TxnRegister;
+
+impl Checker for TxnRegister {
+    fn name(&self) -> &'static str {
+        "txn-rw-register"
+    }
+
+    fn check(&self, operations: &[Operation]) -> Vec<Anomaly> {
+        let transactions = operations.iter()
+            .filter(|operation| operation.request_type() == "txn")
+            .collect::<Vec<_>>();
+
+        // The transaction that wrote each value, by key and value
+        let mut writers = HashMap::<(String, String), &Operation>::new();
+
+        for transaction in &transactions {
+            for (key, value) in writes(&transaction.request) {
+                writers.insert((key, value.to_string()), transaction);
+            }
+        }
+
+        let mut anomalies = Vec::new();
+
+        for transaction in &transactions {
+            let Some(reply) = transaction.ok_reply() else {
+                continue;
+            };
+
+            let mut written = HashMap::<String, &Value>::new();
+
+            for operation in reply["txn"].as_array().into_iter().flatten() {
+                let key = key(&operation[1]);
+                let value = &operation[2];
+
+                if operation[0] == "w" {
+                    written.insert(key, value);
+                    continue;
+                }
+
+                let anomaly = match (written.get(&key), writers.get(&(key.clone(), value.to_string()))) {
+                    (Some(own), _) if *own != value => Some(("internal", format!("Read {value} from {key} after writing {own} to it"))),
+                    (Some(_), _) => None,
+                    (None, _) if value.is_null() => None,
+                    (None, None) => Some(("garbage-read", format!("Read {value} from {key}, which was never written"))),
+                    (None, Some(writer)) if writer.outcome == Outcome::Fail => {
+                        Some(("aborted-read", format!("Read {value} from {key}, written by a transaction that failed")))
+                    },
+                    (None, Some(writer)) if writer.index == transaction.index => {
+                        Some(("future-read", format!("Read {value} from {key} before writing it")))
+                    },
+                    (None, Some(_)) => None,
+                };
+
+                if let Some((kind, description)) = anomaly {
+                    let writer = writers.get(&(key, value.to_string())).filter(|writer| writer.index != transaction.index);
+                    let operations = writer.into_iter().chain([transaction]).map(|operation| (*operation).clone()).collect();
+
+                    anomalies.push(Anomaly { kind, description, operations });
+                }
+            }
+        }
+
+        anomalies
+    }
+}
+
+/// The keys and values a transaction writes
+fn writes(body: &Value) -> impl Iterator<Item = (String, &Value)> {
+    body["txn"].as_array().into_iter().flatten()
+        .filter(|operation| operation[0] == "w")
+        .map(|operation| (key(&operation[1]), &operation[2]))
+}
+
+fn key(value: &Value) -> String {
+    value.as_str().map_or_else(|| value.to_string(), str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use serde_json::json;
+    use super::*;
+
+    /// An operation on `node` that ran from `invoked` to `completed` milliseconds, failing if `reply` is an error
+    fn operation(node: &str, request: Value, reply: Option<Value>, invoked: u64, completed: u64) -> Operation {
+        let outcome = match &reply {
+            Some(reply) if reply["type"] == "error" => Outcome::Fail,
+            Some(_) => Outcome::Ok,
+            None => Outcome::Unknown,
+        };
+
+        Operation {
+            index: 0,
+            client: "c1".to_string(),
+            node: node.to_string(),
+            request,
+            outcome,
+            completed_at: reply.as_ref().map(|_| Duration::from_millis(completed)),
+            reply,
+            invoked_at: Duration::from_millis(invoked),
+        }
+    }
+
+    fn check(checker: &dyn Checker, mut operations: Vec<Operation>) -> Vec<&'static str> {
+        for (index, operation) in operations.iter_mut().enumerate() {
+            operation.index = index;
+        }
+
+        checker.check(&operations).into_iter().map(|anomaly| anomaly.kind).collect()
+    }
+
+    fn broadcast(message: u64, invoked: u64, completed: u64) -> Operation {
+        operation("n0", json!({ "type": "broadcast", "message": message }), Some(json!({ "type": "broadcast_ok" })), invoked, completed)
+    }
+
+    fn read_messages(node: &str, messages: Value, invoked: u64, completed: u64) -> Operation {
+        operation(node, json!({ "type": "read" }), Some(json!({ "type": "read_ok", "messages": messages })), invoked, completed)
+    }
+
+    #[test]
+    fn set_full_needs_every_message_on_every_node() {
+        let converged = vec![
+            broadcast(1, 0, 1),
+            read_messages("n1", json!([]), 2, 3),
+            read_messages("n1", json!([1]), 4, 5),
+            read_messages("n0", json!([1]), 6, 7),
+        ];
+        assert_eq!(check(&SetFull, converged), Vec::<&str>::new());
+
+        let lost = vec![
+            broadcast(1, 0, 1),
+            read_messages("n1", json!([]), 2, 3),
+            read_messages("n0", json!([1]), 4, 5),
+        ];
+        assert_eq!(check(&SetFull, lost), ["lost"]);
+
+        let invented = vec![broadcast(1, 0, 1), read_messages("n0", json!([1, 2]), 2, 3)];
+        assert_eq!(check(&SetFull, invented), ["unexpected"]);
+    }
+
+    #[test]
+    fn counter_reads_must_lie_within_the_adds() {
+        let add = |delta: i64, invoked, completed| {
+            operation("n0", json!({ "type": "add", "delta": delta }), Some(json!({ "type": "add_ok" })), invoked, completed)
+        };
+        let read = |value: i64, invoked, completed| {
+            operation("n1", json!({ "type": "read" }), Some(json!({ "type": "read_ok", "value": value })), invoked, completed)
+        };
+
+        // The second add overlaps the reads, so they may or may not include it
+        let adds = || vec![add(3, 0, 1), add(2, 2, 10)];
+
+        for (value, anomalies) in [(3, vec![]), (5, vec![]), (2, vec!["out-of-bounds"]), (6, vec!["out-of-bounds"])] {
+            let mut operations = adds();
+            operations.push(read(value, 3, 4));
+            assert_eq!(check(&CounterBounds, operations), anomalies, "read {value}");
+        }
+    }
+
+    fn send(key: &str, msg: u64, offset: u64, invoked: u64, completed: u64) -> Operation {
+        operation("n0", json!({ "type": "send", "key": key, "msg": msg }), Some(json!({ "type": "send_ok", "offset": offset })), invoked, completed)
+    }
+
+    fn poll(key: &str, from: u64, msgs: Value, invoked: u64, completed: u64) -> Operation {
+        let request = json!({ "type": "poll", "offsets": { key: from } });
+        operation("n1", request, Some(json!({ "type": "poll_ok", "msgs": { key: msgs } })), invoked, completed)
+    }
+
+    #[test]
+    fn kafka_polls_must_be_ordered_and_complete() {
+        let sends = || vec![send("a", 10, 0, 0, 1), send("a", 11, 1, 2, 3), send("a", 12, 2, 4, 5)];
+
+        let mut complete = sends();
+        complete.push(poll("a", 0, json!([[0, 10], [1, 11], [2, 12]]), 6, 7));
+        assert_eq!(check(&KafkaLog, complete), Vec::<&str>::new());
+
+        let mut gap = sends();
+        gap.push(poll("a", 0, json!([[0, 10], [2, 12]]), 6, 7));
+        assert_eq!(check(&KafkaLog, gap), ["lost-write"]);
+
+        let mut reordered = sends();
+        reordered.push(poll("a", 0, json!([[0, 10], [2, 12], [1, 11]]), 6, 7));
+        assert_eq!(check(&KafkaLog, reordered), ["nonmonotonic-poll"]);
+
+        let mut backwards = sends();
+        backwards.push(send("a", 13, 1, 6, 7));
+        assert_eq!(check(&KafkaLog, backwards), ["duplicate-offset", "nonmonotonic-send"]);
+    }
+
+    #[test]
+    fn committed_offsets_only_regress_when_another_commit_explains_it() {
+        let commit = |offset: u64, invoked, completed| {
+            operation("n0", json!({ "type": "commit_offsets", "offsets": { "a": offset } }), Some(json!({ "type": "commit_offsets_ok" })), invoked, completed)
+        };
+        let list = |keys: Value, offsets: Value, invoked, completed| {
+            let request = json!({ "type": "list_committed_offsets", "keys": keys });
+            operation("n1", request, Some(json!({ "type": "list_committed_offsets_ok", "offsets": offsets })), invoked, completed)
+        };
+
+        let regressed = vec![commit(5, 0, 1), list(json!(["a"]), json!({ "a": 3 }), 2, 3)];
+        assert_eq!(check(&KafkaLog, regressed), ["committed-offset-regression"]);
+
+        let missing = vec![commit(5, 0, 1), list(json!(["a"]), json!({}), 2, 3)];
+        assert_eq!(check(&KafkaLog, missing), ["committed-offset-regression"]);
+
+        // Another client committed 3 concurrently, so it may have landed last
+        let overwritten = vec![commit(5, 0, 1), commit(3, 0, 4), list(json!(["a"]), json!({ "a": 3 }), 2, 3)];
+        assert_eq!(check(&KafkaLog, overwritten), Vec::<&str>::new());
+
+        // A commit that finished before the other one started can't explain it
+        let superseded = vec![commit(3, 0, 1), commit(5, 2, 3), list(json!(["a"]), json!({ "a": 3 }), 4, 5)];
+        assert_eq!(check(&KafkaLog, superseded), ["committed-offset-regression"]);
+
+        let unlisted = vec![commit(5, 0, 1), list(json!(["b"]), json!({}), 2, 3)];
+        assert_eq!(check(&KafkaLog, unlisted), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn lin_kv_reads_must_not_be_stale() {
+        let write = |value: u64, invoked, completed| {
+            operation("n0", json!({ "type": "write", "key": 0, "value": value }), Some(json!({ "type": "write_ok" })), invoked, completed)
+        };
+        let read = |value: u64, invoked, completed| {
+            operation("n1", json!({ "type": "read", "key": 0 }), Some(json!({ "type": "read_ok", "value": value })), invoked, completed)
+        };
+
+        let concurrent = vec![write(1, 0, 1), write(2, 2, 5), read(1, 3, 4)];
+        assert_eq!(check(&LinearizableRegister, concurrent), Vec::<&str>::new());
+
+        let stale = vec![write(1, 0, 1), write(2, 2, 3), read(1, 4, 5)];
+        assert_eq!(check(&LinearizableRegister, stale), ["stale-read"]);
+
+        // Written again after being overwritten, so reading it is fine
+        let rewritten = vec![write(1, 0, 1), write(2, 2, 3), write(1, 4, 5), read(1, 6, 7)];
+        assert_eq!(check(&LinearizableRegister, rewritten), Vec::<&str>::new());
+
+        let invented = vec![write(1, 0, 1), read(3, 2, 3)];
+        assert_eq!(check(&LinearizableRegister, invented), ["unexpected"]);
+    }
+
+    #[test]
+    fn txn_reads_must_come_from_committed_writes() {
+        let txn = |request: Value, reply: Value, invoked, completed| {
+            operation("n0", json!({ "type": "txn", "txn": request }), Some(reply), invoked, completed)
+        };
+        let ok = |txn: Value| json!({ "type": "txn_ok", "txn": txn });
+
+        let aborted = vec![
+            txn(json!([["w", 0, 7]]), json!({ "type": "error", "code": 30 }), 0, 1),
+            txn(json!([["r", 0, null]]), ok(json!([["r", 0, 7]])), 2, 3),
+        ];
+        assert_eq!(check(&TxnRegister, aborted), ["aborted-read"]);
+
+        let internal = vec![
+            txn(json!([["w", 0, 7]]), ok(json!([["w", 0, 7]])), 0, 1),
+            txn(json!([["w", 0, 8], ["r", 0, null]]), ok(json!([["w", 0, 8], ["r", 0, 7]])), 2, 3),
+        ];
+        assert_eq!(check(&TxnRegister, internal), ["internal"]);
+
+        let garbage = vec![txn(json!([["r", 0, null]]), ok(json!([["r", 0, 9]])), 0, 1)];
+        assert_eq!(check(&TxnRegister, garbage), ["garbage-read"]);
+
+        let committed = vec![
+            txn(json!([["w", 0, 7]]), ok(json!([["w", 0, 7]])), 0, 1),
+            txn(json!([["r", 0, null], ["r", 1, null]]), ok(json!([["r", 0, 7], ["r", 1, null]])), 2, 3),
+        ];
+        assert_eq!(check(&TxnRegister, committed), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn unique_ids_must_not_repeat() {
+        let generate = |id: u64| operation("n0", json!({ "type": "generate" }), Some(json!({ "type": "generate_ok", "id": id })), 0, 1);
+
+        assert_eq!(check(&UniqueIds, vec![generate(1), generate(2)]), Vec::<&str>::new());
+        assert_eq!(check(&UniqueIds, vec![generate(1), generate(2), generate(1)]), ["duplicate-id"]);
+    }
+}
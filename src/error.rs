@@ -0,0 +1,136 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+use color_eyre::Report;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "i32", into = "i32")]
+pub enum ErrorCode {
+    Timeout,
+    NodeNotFound,
+    NotSupported,
+    TemporarilyUnavailable,
+    MalformedRequest,
+    Crash,
+    Abort,
+    KeyDoesNotExist,
+    KeyAlreadyExists,
+    PreconditionFailed,
+    TxnConflict,
+    Custom(i32),
+}
+
+impl ErrorCode {
+    /// Whether the error guarantees the request had no effect. Custom codes are assumed to be indefinite.
+    pub fn is_definite(&self) -> bool {
+        !matches!(self, ErrorCode::Timeout | ErrorCode::Crash | ErrorCode::Custom(_))
+    }
+}
+
+impl From<i32> for ErrorCode {
+    fn from(code: i32) -> Self {
+        match code {
+            0 => ErrorCode::Timeout,
+            1 => ErrorCode::NodeNotFound,
+            10 => ErrorCode::NotSupported,
+            11 => ErrorCode::TemporarilyUnavailable,
+            12 => ErrorCode::MalformedRequest,
+            13 => ErrorCode::Crash,
+            14 => ErrorCode::Abort,
+            20 => ErrorCode::KeyDoesNotExist,
+            21 => ErrorCode::KeyAlreadyExists,
+            22 => ErrorCode::PreconditionFailed,
+            30 => ErrorCode::TxnConflict,
+            code => ErrorCode::Custom(code),
+        }
+    }
+}
+
+impl From<ErrorCode> for i32 {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::Timeout => 0,
+            ErrorCode::NodeNotFound => 1,
+            ErrorCode::NotSupported => 10,
+            ErrorCode::TemporarilyUnavailable => 11,
+            ErrorCode::MalformedRequest => 12,
+            ErrorCode::Crash => 13,
+            ErrorCode::Abort => 14,
+            ErrorCode::KeyDoesNotExist => 20,
+            ErrorCode::KeyAlreadyExists => 21,
+            ErrorCode::PreconditionFailed => 22,
+            ErrorCode::TxnConflict => 30,
+            ErrorCode::Custom(code) => code,
+        }
+    }
+}
+
+/// The payload of a Maelstrom `error` message. Returning one from a handler (e.g. via `Err(error.into())`)
+/// replies to the requester with that code, while any other error is reported as a crash.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "error")]
+pub struct MaelstromError {
+    pub code: ErrorCode,
+    #[serde(default)]
+    pub text: String,
+}
+
+impl MaelstromError {
+    pub fn new(code: ErrorCode, text: impl Into<String>) -> Self {
+        Self {
+            code,
+            text: text.into(),
+        }
+    }
+
+    pub fn timeout(text: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Timeout, text)
+    }
+
+    pub fn not_supported(text: impl Into<String>) -> Self {
+        Self::new(ErrorCode::NotSupported, text)
+    }
+
+    pub fn malformed_request(text: impl Into<String>) -> Self {
+        Self::new(ErrorCode::MalformedRequest, text)
+    }
+
+    pub fn crash(text: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Crash, text)
+    }
+
+    pub fn key_does_not_exist(text: impl Into<String>) -> Self {
+        Self::new(ErrorCode::KeyDoesNotExist, text)
+    }
+
+    pub fn precondition_failed(text: impl Into<String>) -> Self {
+        Self::new(ErrorCode::PreconditionFailed, text)
+    }
+}
+
+impl Display for MaelstromError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} (code {}): {}", self.code, i32::from(self.code), self.text)
+    }
+}
+
+impl Error for MaelstromError {}
+
+/// What a node does when a handler fails with anything but a `MaelstromError` (which is always sent back as is),
+/// panics included, or when a periodic task fails
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FailurePolicy {
+    /// Log the failure and carry on. The requester gets no reply and failed tasks run again next period.
+    LogAndContinue,
+    /// Like `LogAndContinue`, but handler failures are also answered with a `crash` error
+    #[default]
+    ReplyWithError,
+    /// Stop the node, returning the failure from `NodeServer::serve`
+    Crash,
+}
+
+/// The Maelstrom error code carried by `err`, if it wraps a `MaelstromError`
+pub fn error_code(err: &Report) -> Option<ErrorCode> {
+    err.downcast_ref::<MaelstromError>().map(|err| err.code)
+}
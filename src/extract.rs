@@ -0,0 +1,168 @@
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::RwLock;
+
+use color_eyre::eyre::OptionExt;
+use color_eyre::Result;
+use serde::de::DeserializeOwned;
+use crate::{KVStore, LINEARIZABLE_KV_STORE_ID, LWW_KV_STORE_ID, Message, Node, NodeId, SEQUENTIAL_KV_STORE_ID};
+use crate::error::MaelstromError;
+
+/// Types that can be taken from an incoming message as a handler argument
+pub trait FromMessage<S>: Sized {
+    fn from_message(node: &Node<S>, message: &Message<serde_json::Value>) -> Result<Self>;
+}
+
+impl<S> FromMessage<S> for Node<S> {
+    fn from_message(node: &Node<S>, _message: &Message<serde_json::Value>) -> Result<Self> {
+        Ok(node.clone())
+    }
+}
+
+impl<S, P: DeserializeOwned> FromMessage<S> for Message<P> {
+    fn from_message(node: &Node<S>, message: &Message<serde_json::Value>) -> Result<Self> {
+        let Payload(payload) = Payload::from_message(node, message)?;
+        Ok(message.without_payload().with_payload(payload))
+    }
+}
+
+/// The node's shared state, dereferencing to the `RwLock` it lives in
+pub struct State<S>(Node<S>);
+
+impl<S> Deref for State<S> {
+    type Target = RwLock<S>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0.state
+    }
+}
+
+impl<S> FromMessage<S> for State<S> {
+    fn from_message(node: &Node<S>, _message: &Message<serde_json::Value>) -> Result<Self> {
+        Ok(State(node.clone()))
+    }
+}
+
+pub struct Payload<T>(pub T);
+
+impl<S, T: DeserializeOwned> FromMessage<S> for Payload<T> {
+    fn from_message(_node: &Node<S>, message: &Message<serde_json::Value>) -> Result<Self> {
+        let payload = serde_json::from_value(message.body.payload.clone())
+            .map_err(|err| MaelstromError::malformed_request(err.to_string()))?;
+
+        Ok(Payload(payload))
+    }
+}
+
+pub struct Src(pub NodeId);
+
+impl<S> FromMessage<S> for Src {
+    fn from_message(_node: &Node<S>, message: &Message<serde_json::Value>) -> Result<Self> {
+        Ok(Src(message.src.clone()))
+    }
+}
+
+pub struct MessageId(pub Option<i32>);
+
+impl<S> FromMessage<S> for MessageId {
+    fn from_message(_node: &Node<S>, message: &Message<serde_json::Value>) -> Result<Self> {
+        Ok(MessageId(message.body.message_id))
+    }
+}
+
+pub struct NodeIdentity {
+    pub node_id: NodeId,
+    pub node_ids: Vec<NodeId>,
+}
+
+impl<S> FromMessage<S> for NodeIdentity {
+    fn from_message(node: &Node<S>, _message: &Message<serde_json::Value>) -> Result<Self> {
+        Ok(NodeIdentity {
+            node_id: node.node_id.clone(),
+            node_ids: node.node_ids.clone(),
+        })
+    }
+}
+
+pub trait KvService {
+    const ID: &'static str;
+}
+
+pub struct SeqKv;
+pub struct LinKv;
+pub struct LwwKv;
+
+impl KvService for SeqKv {
+    const ID: &'static str = SEQUENTIAL_KV_STORE_ID;
+}
+
+impl KvService for LinKv {
+    const ID: &'static str = LINEARIZABLE_KV_STORE_ID;
+}
+
+impl KvService for LwwKv {
+    const ID: &'static str = LWW_KV_STORE_ID;
+}
+
+/// Client for one of the key-value services, `seq-kv` unless another `KvService` is given
+pub struct KvClient<K = SeqKv> {
+    store: KVStore,
+    _service: PhantomData<K>,
+}
+
+impl<K> Deref for KvClient<K> {
+    type Target = KVStore;
+
+    fn deref(&self) -> &Self::Target {
+        &self.store
+    }
+}
+
+impl<S, K: KvService> FromMessage<S> for KvClient<K> {
+    fn from_message(node: &Node<S>, _message: &Message<serde_json::Value>) -> Result<Self> {
+        let client = node.services.get(K::ID).ok_or_eyre("Key-value service is not registered")?;
+
+        Ok(KvClient {
+            store: KVStore::new(client.clone()),
+            _service: PhantomData,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use serde_json::{json, Value};
+    use crate::{NodeServer, Reply, Router};
+    use crate::error::{error_code, ErrorCode};
+    use crate::simulator::{NetworkConfig, Simulator};
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Add {
+        delta: i64,
+    }
+
+    async fn add(Src(src): Src, MessageId(message_id): MessageId, Payload(Add { delta }): Payload<Add>) -> Reply<Value> {
+        Reply(json!({ "type": "add_ok", "src": src, "msg_id_seen": message_id, "delta": delta }))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn malformed_payloads_are_malformed_requests() {
+        let simulator = Simulator::start(1, NetworkConfig::default(), || {
+            NodeServer::new((), Router::new().route("add", add))
+        }).await.unwrap();
+
+        let reply: Value = simulator.request_from("c2", "n0", json!({ "type": "add", "delta": 3 })).await.unwrap();
+        assert_eq!(reply["src"], "c2");
+        assert!(reply["msg_id_seen"].is_i64());
+        assert_eq!(reply["delta"], 3);
+
+        for payload in [json!({ "type": "add", "delta": "three" }), json!({ "type": "add" })] {
+            let err = simulator.request::<_, Value>("n0", payload).await.unwrap_err();
+            assert_eq!(error_code(&err), Some(ErrorCode::MalformedRequest));
+        }
+
+        simulator.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,95 @@
+use std::future::Future;
+
+use color_eyre::Report;
+use futures::future::BoxFuture;
+use serde::Serialize;
+use crate::{Message, MessageReply, Node};
+use crate::extract::FromMessage;
+
+/// Types a handler can return. Replies are addressed to the sender of the request automatically.
+pub trait IntoReply {
+    fn reply_to(self, request: Message<()>) -> MessageReply<serde_json::Value>;
+}
+
+/// A reply payload, sent back to the requester
+pub struct Reply<P>(pub P);
+
+impl<P: Serialize> IntoReply for Reply<P> {
+    fn reply_to(self, request: Message<()>) -> MessageReply<serde_json::Value> {
+        Ok(Some(request.into_reply(serde_json::to_value(self.0)?)))
+    }
+}
+
+impl<P: Serialize> IntoReply for Message<P> {
+    fn reply_to(self, _request: Message<()>) -> MessageReply<serde_json::Value> {
+        let (message, payload) = self.take_payload();
+        Ok(Some(message.with_payload(serde_json::to_value(payload)?)))
+    }
+}
+
+impl IntoReply for () {
+    fn reply_to(self, _request: Message<()>) -> MessageReply<serde_json::Value> {
+        Ok(None)
+    }
+}
+
+impl<T: IntoReply> IntoReply for Option<T> {
+    fn reply_to(self, request: Message<()>) -> MessageReply<serde_json::Value> {
+        match self {
+            Some(reply) => reply.reply_to(request),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<T, E> IntoReply for Result<T, E>
+where
+    T: IntoReply,
+    E: Into<Report>,
+{
+    fn reply_to(self, request: Message<()>) -> MessageReply<serde_json::Value> {
+        self.map_err(Into::into)?.reply_to(request)
+    }
+}
+
+/// Async functions taking any number of `FromMessage` extractors and returning an `IntoReply`
+pub trait Handler<T, S>: Send + Sync + 'static {
+    fn call(&self, node: Node<S>, message: Message<serde_json::Value>) -> BoxFuture<'static, MessageReply<serde_json::Value>>;
+}
+
+macro_rules! impl_handler {
+    ($($extractor:ident),*) => {
+        #[allow(non_snake_case, unused_variables)]
+        impl<F, Fut, R, S, $($extractor,)*> Handler<($($extractor,)*), S> for F
+        where
+            F: Fn($($extractor),*) -> Fut + Send + Sync + 'static,
+            Fut: Future<Output = R> + Send + 'static,
+            R: IntoReply,
+            $($extractor: FromMessage<S>,)*
+        {
+            fn call(&self, node: Node<S>, message: Message<serde_json::Value>) -> BoxFuture<'static, MessageReply<serde_json::Value>> {
+                $(
+                    let $extractor = match $extractor::from_message(&node, &message) {
+                        Ok(value) => value,
+                        Err(err) => return Box::pin(async move { Err(err) }),
+                    };
+                )*
+
+                let reply = (self)($($extractor),*);
+                let request = message.without_payload();
+
+                Box::pin(async move { reply.await.reply_to(request) })
+            }
+        }
+    };
+}
+
+impl_handler!();
+impl_handler!(T1);
+impl_handler!(T1, T2);
+impl_handler!(T1, T2, T3);
+impl_handler!(T1, T2, T3, T4);
+impl_handler!(T1, T2, T3, T4, T5);
+impl_handler!(T1, T2, T3, T4, T5, T6);
+impl_handler!(T1, T2, T3, T4, T5, T6, T7);
+impl_handler!(T1, T2, T3, T4, T5, T6, T7, T8);
@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::time::Instant;
+use crate::{MaelstromError, NodeId};
+use crate::checker::{CheckReport, Checker};
+
+/// A client request and what became of it, as seen from outside the cluster
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Operation {
+    pub index: usize,
+    pub client: NodeId,
+    pub node: NodeId,
+    /// The request body, `msg_id` included
+    pub request: Value,
+    pub outcome: Outcome,
+    pub reply: Option<Value>,
+    /// Time since the history was started
+    pub invoked_at: Duration,
+    pub completed_at: Option<Duration>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Ok,
+    /// Answered with a definite error, so the request had no effect
+    Fail,
+    /// Not answered (yet), or answered with an indefinite error. The request may or may not have taken effect.
+    Unknown,
+}
+
+impl Operation {
+    pub fn request_type(&self) -> &str {
+        self.request["type"].as_str().unwrap_or_default()
+    }
+
+    /// The reply, if the request succeeded
+    pub fn ok_reply(&self) -> Option<&Value> {
+        self.reply.as_ref().filter(|_| self.outcome == Outcome::Ok)
+    }
+
+    /// Whether the operation finished before `other` started, so `other` must observe its effects
+    pub fn precedes(&self, other: &Operation) -> bool {
+        self.completed_at.is_some_and(|completed_at| completed_at < other.invoked_at)
+    }
+}
+
+/// Records the requests clients send to a cluster and the replies they get. Cloning it gives another handle to the
+/// same history.
+#[derive(Clone)]
+pub struct History {
+    inner: Arc<Mutex<HistoryInner>>,
+}
+
+struct HistoryInner {
+    start: Instant,
+    operations: Vec<Operation>,
+    // Operations still waiting for a reply, by client and msg_id
+    pending: HashMap<(NodeId, i64), usize>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(HistoryInner {
+                start: Instant::now(),
+                operations: Vec::new(),
+                pending: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Records a request sent by a client. Messages without a `msg_id` expect no reply and are ignored.
+    pub fn record_request(&self, message: &Value) {
+        let (Some(client), Some(node), Some(message_id)) = (
+            message["src"].as_str(),
+            message["dest"].as_str(),
+            message["body"]["msg_id"].as_i64(),
+        ) else {
+            return;
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        let index = inner.operations.len();
+        let invoked_at = inner.start.elapsed();
+
+        inner.operations.push(Operation {
+            index,
+            client: client.to_string(),
+            node: node.to_string(),
+            request: message["body"].clone(),
+            outcome: Outcome::Unknown,
+            reply: None,
+            invoked_at,
+            completed_at: None,
+        });
+        inner.pending.insert((client.to_string(), message_id), index);
+    }
+
+    /// Completes the operation `message` replies to. Replies to requests that weren't recorded are ignored.
+    pub fn record_reply(&self, message: &Value) {
+        let (Some(client), Some(in_reply_to)) = (message["dest"].as_str(), message["body"]["in_reply_to"].as_i64()) else {
+            return;
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+
+        let Some(index) = inner.pending.remove(&(client.to_string(), in_reply_to)) else {
+            return;
+        };
+
+        let completed_at = inner.start.elapsed();
+        let reply = message["body"].clone();
+
+        let outcome = match serde_json::from_value::<MaelstromError>(reply.clone()) {
+            Ok(error) if error.code.is_definite() => Outcome::Fail,
+            Ok(_) => Outcome::Unknown,
+            Err(_) => Outcome::Ok,
+        };
+
+        let operation = &mut inner.operations[index];
+        operation.outcome = outcome;
+        operation.reply = Some(reply);
+        operation.completed_at = Some(completed_at);
+    }
+
+    pub fn operations(&self) -> Vec<Operation> {
+        self.inner.lock().unwrap().operations.clone()
+    }
+
+    pub fn check(&self, checker: &dyn Checker) -> CheckReport {
+        checker.report(&self.operations())
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    fn request(client: &str, msg_id: i64, body: Value) -> Value {
+        let mut body = body;
+        body["msg_id"] = json!(msg_id);
+        json!({ "src": client, "dest": "n0", "body": body })
+    }
+
+    fn reply(client: &str, in_reply_to: i64, body: Value) -> Value {
+        let mut body = body;
+        body["in_reply_to"] = json!(in_reply_to);
+        json!({ "src": "n0", "dest": client, "body": body })
+    }
+
+    #[test]
+    fn replies_complete_their_requests() {
+        let history = History::new();
+
+        history.record_request(&request("c1", 1, json!({ "type": "read" })));
+        history.record_request(&request("c2", 1, json!({ "type": "read" })));
+        history.record_reply(&reply("c2", 1, json!({ "type": "read_ok", "value": 2 })));
+
+        let operations = history.operations();
+        assert_eq!(operations.len(), 2);
+
+        assert_eq!((operations[0].client.as_str(), operations[0].outcome), ("c1", Outcome::Unknown));
+        assert!(operations[0].reply.is_none() && operations[0].completed_at.is_none());
+
+        assert_eq!((operations[1].client.as_str(), operations[1].outcome), ("c2", Outcome::Ok));
+        assert_eq!(operations[1].ok_reply().unwrap()["value"], 2);
+        assert!(operations[1].completed_at.is_some());
+    }
+
+    #[test]
+    fn only_definite_errors_fail() {
+        let history = History::new();
+
+        history.record_request(&request("c1", 1, json!({ "type": "cas" })));
+        history.record_request(&request("c1", 2, json!({ "type": "cas" })));
+        history.record_reply(&reply("c1", 1, json!({ "type": "error", "code": 22 })));
+        history.record_reply(&reply("c1", 2, json!({ "type": "error", "code": 0 })));
+
+        let outcomes = history.operations().iter().map(|operation| operation.outcome).collect::<Vec<_>>();
+        assert_eq!(outcomes, [Outcome::Fail, Outcome::Unknown]);
+        assert!(history.operations()[1].ok_reply().is_none());
+    }
+
+    #[test]
+    fn unmatched_messages_are_ignored() {
+        let history = History::new();
+
+        history.record_request(&json!({ "src": "c1", "dest": "n0", "body": { "type": "topology" } }));
+        history.record_reply(&reply("c1", 7, json!({ "type": "read_ok" })));
+        assert!(history.operations().is_empty());
+
+        // Replies only complete an operation once
+        history.record_request(&request("c1", 1, json!({ "type": "read" })));
+        history.record_reply(&reply("c1", 1, json!({ "type": "read_ok", "value": 1 })));
+        history.record_reply(&reply("c1", 1, json!({ "type": "read_ok", "value": 2 })));
+        assert_eq!(history.operations()[0].ok_reply().unwrap()["value"], 1);
+    }
+
+    #[test]
+    fn precedes_needs_a_completion_before_the_invocation() {
+        let history = History::new();
+        history.record_request(&request("c1", 1, json!({ "type": "read" })));
+
+        let mut first = history.operations().remove(0);
+        let mut second = first.clone();
+
+        first.completed_at = Some(Duration::from_millis(5));
+        second.invoked_at = Duration::from_millis(6);
+        assert!(first.precedes(&second) && !second.precedes(&first));
+
+        second.invoked_at = Duration::from_millis(4);
+        assert!(!first.precedes(&second));
+
+        first.completed_at = None;
+        assert!(!first.precedes(&second));
+    }
+}
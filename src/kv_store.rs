@@ -0,0 +1,323 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use tracing::instrument;
+use crate::error::{error_code, ErrorCode, MaelstromError};
+use crate::service::ServiceClient;
+
+pub const SEQUENTIAL_KV_STORE_ID: &str = "seq-kv";
+pub const LINEARIZABLE_KV_STORE_ID: &str = "lin-kv";
+pub const LWW_KV_STORE_ID: &str = "lww-kv";
+
+/// How many times `compare_and_swap_loop` tries its `cas` before giving up on a contended key
+pub const MAX_CAS_LOOP_ATTEMPTS: u32 = 100;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CasOutcome<D> {
+    Ok,
+    /// The key held a different value. `current` is read back after the failure, so on `seq-kv` it may be stale.
+    PreconditionFailed {
+        current: D,
+    },
+    KeyMissing,
+}
+
+/// Common interface of the Maelstrom key-value services and `MemoryKVStore`. Values are stored as JSON
+/// strings; a missing key is reported as a `KeyDoesNotExist` error and a failed `cas` as `PreconditionFailed`.
+pub trait KeyValueStore: Send + Sync {
+    fn read<D: DeserializeOwned>(&self, key: String) -> impl Future<Output = Result<D>> + Send;
+
+    fn write<S: Serialize + Send>(&self, key: String, value: S) -> impl Future<Output = Result<()>> + Send;
+
+    fn cas_with<D>(&self, key: String, from: D, to: D, create_if_not_exists: bool) -> impl Future<Output = Result<CasOutcome<D>>> + Send
+    where
+        D: Serialize + DeserializeOwned + Send;
+
+    /// Creates the key only if it doesn't exist yet
+    fn create<D>(&self, key: String, value: D) -> impl Future<Output = Result<CasOutcome<D>>> + Send
+    where
+        D: Serialize + DeserializeOwned + Send;
+
+    fn cas<S>(&self, key: String, from: S, to: S) -> impl Future<Output = Result<()>> + Send
+    where
+        S: Serialize + DeserializeOwned + Send,
+    {
+        async move {
+            match self.cas_with(key.clone(), from, to, true).await? {
+                CasOutcome::Ok => Ok(()),
+                CasOutcome::PreconditionFailed { .. } => {
+                    Err(MaelstromError::precondition_failed(format!("Value of {key} did not match")).into())
+                },
+                CasOutcome::KeyMissing => Err(MaelstromError::key_does_not_exist(format!("Key {key} does not exist")).into()),
+            }
+        }
+    }
+
+    /// Reads `key`, applies `f` to its value (`None` if missing) and writes the result back with a `cas`,
+    /// retrying with the new value whenever another writer got there first. Returns the value written, or a
+    /// `TxnConflict` error after `MAX_CAS_LOOP_ATTEMPTS` lost races.
+    fn compare_and_swap_loop<D, F>(&self, key: String, mut f: F) -> impl Future<Output = Result<D>> + Send
+    where
+        D: Serialize + DeserializeOwned + Clone + Send,
+        F: FnMut(Option<&D>) -> D + Send,
+    {
+        async move {
+            let mut current = match self.read::<D>(key.clone()).await {
+                Ok(value) => Some(value),
+                Err(err) if error_code(&err) == Some(ErrorCode::KeyDoesNotExist) => None,
+                Err(err) => return Err(err),
+            };
+
+            for _ in 0..MAX_CAS_LOOP_ATTEMPTS {
+                let new = f(current.as_ref());
+
+                let outcome = match current {
+                    Some(current) => self.cas_with(key.clone(), current, new.clone(), false).await?,
+                    None => self.create(key.clone(), new.clone()).await?,
+                };
+
+                current = match outcome {
+                    CasOutcome::Ok => return Ok(new),
+                    CasOutcome::PreconditionFailed { current } => Some(current),
+                    CasOutcome::KeyMissing => None,
+                };
+            }
+
+            Err(MaelstromError::new(ErrorCode::TxnConflict, format!("Gave up on {key} after {MAX_CAS_LOOP_ATTEMPTS} conflicting writes")).into())
+        }
+    }
+}
+
+/// Client for Maelstrom's `seq-kv`, `lin-kv` and `lww-kv` services, which all share the same protocol
+pub struct KVStore {
+    client: Arc<ServiceClient>,
+}
+
+impl KVStore {
+    pub fn new(client: Arc<ServiceClient>) -> Self {
+        Self {
+            client,
+        }
+    }
+
+    pub fn service_id(&self) -> &str {
+        self.client.service_id()
+    }
+}
+
+impl KeyValueStore for KVStore {
+    #[instrument(level = "debug", name = "kv", skip_all, fields(service = self.service_id(), op = "read", key = %key))]
+    async fn read<D: DeserializeOwned>(&self, key: String) -> Result<D> {
+        match self.client.call_idempotent(KVStorePayload::Read { key }).await? {
+            KVStorePayload::ReadOk { value } => Ok(serde_json::from_str(&value)?),
+            _ => Err(eyre!("Wrong {} reply type", self.service_id())),
+        }
+    }
+
+    #[instrument(level = "debug", name = "kv", skip_all, fields(service = self.service_id(), op = "write", key = %key))]
+    async fn write<S: Serialize + Send>(&self, key: String, value: S) -> Result<()> {
+        let value = serde_json::to_string(&value)?;
+
+        match self.client.call(KVStorePayload::Write { key, value }).await? {
+            KVStorePayload::WriteOk => Ok(()),
+            _ => Err(eyre!("Wrong {} reply type", self.service_id())),
+        }
+    }
+
+    async fn cas_with<D>(&self, key: String, from: D, to: D, create_if_not_exists: bool) -> Result<CasOutcome<D>>
+    where
+        D: Serialize + DeserializeOwned + Send,
+    {
+        let from = serde_json::to_string(&from)?;
+        let to = serde_json::to_string(&to)?;
+
+        self.send_cas(key, from, to, create_if_not_exists).await
+    }
+
+    async fn create<D>(&self, key: String, value: D) -> Result<CasOutcome<D>>
+    where
+        D: Serialize + DeserializeOwned + Send,
+    {
+        // Serialized values are never empty, so an empty `from` only succeeds by creating the key
+        let value = serde_json::to_string(&value)?;
+        self.send_cas(key, String::new(), value, true).await
+    }
+}
+
+impl KVStore {
+    #[instrument(level = "debug", name = "kv", skip_all, fields(service = self.service_id(), op = "cas", key = %key))]
+    async fn send_cas<D: DeserializeOwned>(&self, key: String, from: String, to: String, create_if_not_exists: bool) -> Result<CasOutcome<D>> {
+        let reply = self.client.call(KVStorePayload::Cas { key: key.clone(), from, to, create_if_not_exists }).await;
+
+        match reply {
+            Ok(KVStorePayload::CasOk) => Ok(CasOutcome::Ok),
+            Ok(_) => Err(eyre!("Wrong {} reply type", self.service_id())),
+            Err(err) => match error_code(&err) {
+                Some(ErrorCode::PreconditionFailed) => match self.read::<D>(key).await {
+                    Ok(current) => Ok(CasOutcome::PreconditionFailed { current }),
+                    Err(err) if error_code(&err) == Some(ErrorCode::KeyDoesNotExist) => Ok(CasOutcome::KeyMissing),
+                    Err(err) => Err(err),
+                },
+                Some(ErrorCode::KeyDoesNotExist) => Ok(CasOutcome::KeyMissing),
+                _ => Err(err),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum KVStorePayload {
+    Read {
+        key: String,
+    },
+    ReadOk {
+        value: String,
+    },
+    Write {
+        key: String,
+        value: String,
+    },
+    WriteOk,
+    Cas {
+        key: String,
+        from: String,
+        to: String,
+        create_if_not_exists: bool,
+    },
+    CasOk,
+    /// Error replies are surfaced as a `MaelstromError` by `ServiceClient::call`
+    Error {
+        code: ErrorCode,
+        #[serde(default)]
+        text: String,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use crate::{NodeServer, Reply, Router};
+    use crate::extract::KvClient;
+    use crate::simulator::{NetworkConfig, Simulator};
+    use super::*;
+
+    /// Serializes its fields out of the order `serde_json::Value` keeps them in, so reading it back never matches
+    #[derive(Serialize)]
+    struct Unordered {
+        b: i32,
+        a: i32,
+    }
+
+    fn outcome(outcome: CasOutcome<i32>) -> Value {
+        match outcome {
+            CasOutcome::Ok => json!("ok"),
+            CasOutcome::PreconditionFailed { current } => json!({ "current": current }),
+            CasOutcome::KeyMissing => json!("key_missing"),
+        }
+    }
+
+    async fn cas(kv: KvClient) -> Result<Reply<Value>> {
+        kv.write("a".to_string(), 1).await?;
+
+        Ok(Reply(json!({
+            "type": "cas_ok",
+            "stale": outcome(kv.cas_with("a".to_string(), 2, 3, false).await?),
+            "fresh": outcome(kv.cas_with("a".to_string(), 1, 3, false).await?),
+            "missing": outcome(kv.cas_with("b".to_string(), 1, 2, false).await?),
+            "created": outcome(kv.cas_with("c".to_string(), 1, 2, true).await?),
+            "value": kv.read::<i32>("a".to_string()).await?,
+        })))
+    }
+
+    async fn create(kv: KvClient) -> Result<Reply<Value>> {
+        Ok(Reply(json!({
+            "type": "create_ok",
+            "first": outcome(kv.create("a".to_string(), 1).await?),
+            "second": outcome(kv.create("a".to_string(), 2).await?),
+            "value": kv.read::<i32>("a".to_string()).await?,
+        })))
+    }
+
+    async fn increment(kv: KvClient) -> Result<Reply<Value>> {
+        let first = kv.compare_and_swap_loop("a".to_string(), |value: Option<&i32>| value.map_or(1, |value| value + 1)).await?;
+        let second = kv.compare_and_swap_loop("a".to_string(), |value: Option<&i32>| value.map_or(1, |value| value + 1)).await?;
+
+        Ok(Reply(json!({ "type": "increment_ok", "values": [first, second] })))
+    }
+
+    async fn contend(kv: KvClient) -> Result<Reply<Value>> {
+        kv.write("a".to_string(), Unordered { b: 1, a: 2 }).await?;
+
+        let mut attempts = 0;
+        let err = kv.compare_and_swap_loop("a".to_string(), |value: Option<&Value>| {
+            attempts += 1;
+            value.cloned().unwrap_or_default()
+        }).await.unwrap_err();
+
+        Ok(Reply(json!({ "type": "contend_ok", "attempts": attempts, "code": error_code(&err) })))
+    }
+
+    async fn simulator() -> Simulator {
+        Simulator::start(1, NetworkConfig::default(), || {
+            let router = Router::new()
+                .route("cas", cas)
+                .route("create", create)
+                .route("increment", increment)
+                .route("contend", contend);
+
+            NodeServer::new((), router)
+        }).await.unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_cas_reads_back_the_current_value() {
+        let simulator = simulator().await;
+
+        let reply: Value = simulator.request("n0", json!({ "type": "cas" })).await.unwrap();
+        assert_eq!(reply["stale"], json!({ "current": 1 }));
+        assert_eq!(reply["fresh"], "ok");
+        assert_eq!(reply["missing"], "key_missing");
+        assert_eq!(reply["created"], "ok");
+        assert_eq!(reply["value"], 3);
+
+        simulator.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn create_only_succeeds_for_a_new_key() {
+        let simulator = simulator().await;
+
+        let reply: Value = simulator.request("n0", json!({ "type": "create" })).await.unwrap();
+        assert_eq!(reply["first"], "ok");
+        assert_eq!(reply["second"], json!({ "current": 1 }));
+        assert_eq!(reply["value"], 1);
+
+        simulator.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cas_loop_creates_then_updates_the_key() {
+        let simulator = simulator().await;
+
+        let reply: Value = simulator.request("n0", json!({ "type": "increment" })).await.unwrap();
+        assert_eq!(reply["values"], json!([1, 2]));
+
+        simulator.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cas_loop_gives_up_with_a_txn_conflict() {
+        let simulator = simulator().await;
+
+        let reply: Value = simulator.request("n0", json!({ "type": "contend" })).await.unwrap();
+        assert_eq!(reply["attempts"], MAX_CAS_LOOP_ATTEMPTS);
+        assert_eq!(reply["code"], json!(ErrorCode::TxnConflict));
+
+        simulator.shutdown().await.unwrap();
+    }
+}
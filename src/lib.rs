@@ -1,22 +1,66 @@
+use std::any::Any;
 use std::fmt::Debug;
 use std::future::Future;
-use std::io::{BufRead, Write};
 use std::io;
+use std::path::{Path, PathBuf};
 use std::marker::PhantomData;
 use std::ops::Deref;
+use std::panic::AssertUnwindSafe;
+use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
 use color_eyre::eyre::{eyre, OptionExt, Result};
 use color_eyre::Report;
+use futures::{FutureExt, SinkExt};
 use futures::future::BoxFuture;
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 use serde::de::DeserializeOwned;
-use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::oneshot;
 use tokio::task::JoinSet;
-use crate::sequential_kv_store::{SEQUENTIAL_KV_STORE_ID, SequentialKVStore, SequentialKVStorePayload};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, debug_span, error, info, info_span, warn, Instrument, Span};
+use tracing_error::ErrorLayer;
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tower::{Layer, Service, ServiceExt};
+use crate::middleware::{HandlerService, Request, Response, RouterService};
+use crate::rpc::{decode_reply, Outbox};
+use crate::transport::{connect_from_env, Input, Output, Transport};
 
-mod sequential_kv_store;
+pub use crate::error::{error_code, ErrorCode, FailurePolicy, MaelstromError};
+pub use crate::kv_store::{CasOutcome, KeyValueStore, KVStore, LINEARIZABLE_KV_STORE_ID, LWW_KV_STORE_ID, MAX_CAS_LOOP_ATTEMPTS, SEQUENTIAL_KV_STORE_ID};
+pub use crate::memory_kv_store::{Consistency, MemoryKVStore};
+pub use crate::handler::{Handler, IntoReply, Reply};
+pub use crate::router::Router;
+pub use crate::rpc::{Backoff, OutboundStats, RetryPolicy, RpcOptions};
+pub use crate::service::{ServiceClient, TIMESTAMP_ORACLE_ID, TimestampOracle};
+pub use crate::service_emulator::ServiceEmulator;
+pub use crate::stats::{LatencySummary, MessageCounts, Stats, StatsSummary};
+
+pub mod challenges;
+pub mod checker;
+pub mod extract;
+pub mod history;
+pub mod middleware;
+pub mod simulator;
+pub mod topology;
+pub mod transport;
+pub mod workload;
+
+mod error;
+mod handler;
+mod kv_store;
+mod memory_kv_store;
+mod router;
+mod rpc;
+mod service;
+mod service_emulator;
+mod stats;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message<P> {
@@ -26,18 +70,31 @@ pub struct Message<P> {
 }
 
 impl<P> Message<P> {
+    /// Addresses a reply to this message. Its `msg_id` is assigned by the node when the reply is sent.
     pub fn into_reply<P2>(self, payload: P2) -> Message<P2> {
         Message {
             src: self.dest,
             dest: self.src,
             body: MessageBody {
-                message_id: self.body.in_reply_to.map(|id| id + 1),
+                message_id: None,
                 in_reply_to: self.body.message_id,
                 payload,
             }
         }
     }
 
+    pub fn without_payload(&self) -> Message<()> {
+        Message {
+            src: self.src.clone(),
+            dest: self.dest.clone(),
+            body: MessageBody {
+                message_id: self.body.message_id,
+                in_reply_to: self.body.in_reply_to,
+                payload: (),
+            },
+        }
+    }
+
     pub fn take_payload(self) -> (Message<()>, P) {
         (
             Message {
@@ -54,6 +111,20 @@ impl<P> Message<P> {
     }
 }
 
+impl Message<()> {
+    pub fn with_payload<P>(self, payload: P) -> Message<P> {
+        Message {
+            src: self.src,
+            dest: self.dest,
+            body: MessageBody {
+                message_id: self.body.message_id,
+                in_reply_to: self.body.in_reply_to,
+                payload,
+            },
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageBody<P> {
     #[serde(rename = "msg_id", skip_serializing_if = "Option::is_none")]
@@ -80,28 +151,138 @@ pub struct InitOk {
 
 pub type NodeId = String;
 pub type MessageReply<P> = Result<Option<Message<P>>>;
-type MessageHandler<S, P> = Box<dyn Fn(Node<S, P>, Message<P>) -> BoxFuture<'static, MessageReply<P>> + Send + Sync>;
-type TaskHandler<S, P> = Box<dyn Fn(Node<S, P>) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+type TaskHandler<S> = Box<dyn Fn(Node<S>) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+type ShutdownHook<S> = Box<dyn FnOnce(Node<S>) -> BoxFuture<'static, Result<()>> + Send>;
+const DEFAULT_OUTBOUND_CAPACITY: usize = 1024;
+const WRITE_BATCH_SIZE: usize = 256;
+/// How long a failed background task waits before it is started again
+const BACKGROUND_TASK_RESTART_DELAY: Duration = Duration::from_millis(100);
+
+/// The next non-blank line of input, or `None` once the input is closed
+async fn next_line(lines: &mut Input) -> Result<Option<String>> {
+    while let Some(line) = lines.next().await {
+        let line = line?;
+
+        if !line.trim().is_empty() {
+            return Ok(Some(line));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Resolves once the process is asked to terminate
+async fn terminated() -> Result<()> {
+    #[cfg(unix)]
+    tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?.recv().await;
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+
+    Ok(())
+}
+
+fn inbound_span(message: &serde_json::Value) -> Span {
+    let body = &message["body"];
+
+    info_span!(
+        "message",
+        src = message["src"].as_str(),
+        dest = message["dest"].as_str(),
+        r#type = body["type"].as_str(),
+        msg_id = body["msg_id"].as_i64(),
+        in_reply_to = body["in_reply_to"].as_i64(),
+    )
+}
+
+fn write_stats(path: &Path, stats: &StatsSummary) -> Result<()> {
+    std::fs::write(path, serde_json::to_vec_pretty(stats)?)?;
+    Ok(())
+}
+
+/// Mixes an id into `seed` (FNV-1a), so that every node or client of a cluster gets its own random numbers
+fn mix_seed(seed: u64, id: &str) -> u64 {
+    id.bytes().fold(seed ^ 0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3))
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    panic.downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "Box<dyn Any>".to_string())
+}
+
+/// Writes queued messages to `output`, coalescing everything queued at the time into a single flush
+async fn write_messages(mut output: Output, mut message_channel_rx: Receiver<String>, mut close_rx: oneshot::Receiver<()>) -> Result<()> {
+    let mut batch = Vec::with_capacity(WRITE_BATCH_SIZE);
+
+    loop {
+        let received = tokio::select! {
+            received = message_channel_rx.recv_many(&mut batch, WRITE_BATCH_SIZE) => received,
+            _ = &mut close_rx => {
+                // Flush whatever is still queued, later sends fail instead of being silently lost
+                message_channel_rx.close();
+                while message_channel_rx.recv_many(&mut batch, WRITE_BATCH_SIZE).await > 0 {
+                    write_batch(&mut output, &mut batch, &mut message_channel_rx).await?;
+                }
+                break;
+            },
+        };
+
+        if received == 0 {
+            break;
+        }
+
+        write_batch(&mut output, &mut batch, &mut message_channel_rx).await?;
+    }
 
-pub struct Task<S, P> {
-    handler: TaskHandler<S, P>,
-    period: Duration,
+    Ok(())
 }
 
-pub struct NodeInner<S, P> {
+async fn write_batch(output: &mut Output, batch: &mut Vec<String>, message_channel_rx: &mut Receiver<String>) -> Result<()> {
+    for message in batch.drain(..) {
+        output.feed(message).await?;
+    }
+
+    // Messages queued while the output was busy go out with the same flush, up to another batch's worth
+    for _ in 0..WRITE_BATCH_SIZE {
+        match message_channel_rx.try_recv() {
+            Ok(message) => output.feed(message).await?,
+            Err(_) => break,
+        }
+    }
+
+    output.flush().await
+}
+
+pub struct Task<S> {
+    handler: TaskHandler<S>,
+    /// `None` for a background task, which runs once for as long as the node does
+    period: Option<Duration>,
+}
+
+pub struct NodeInner<S> {
     pub node_id: NodeId,
     pub node_ids: Vec<NodeId>,
     pub state: RwLock<S>,
-    pub seq_kv: SequentialKVStore,
-    handler: MessageHandler<S, P>,
-    message_channel_tx: Sender<String>,
+    pub seq_kv: KVStore,
+    pub lin_kv: KVStore,
+    pub lww_kv: KVStore,
+    pub lin_tso: TimestampOracle,
+    services: HashMap<NodeId, Arc<ServiceClient>>,
+    service: HandlerService<S>,
+    outbox: Arc<Outbox>,
+    rpc_options: RpcOptions,
+    failure_policy: FailurePolicy,
+    shutdown: CancellationToken,
+    stats: Arc<Stats>,
+    stats_file: Option<PathBuf>,
 }
 
-pub struct Node<S, P> {
-    inner: Arc<NodeInner<S, P>>,
+pub struct Node<S> {
+    inner: Arc<NodeInner<S>>,
 }
 
-impl<S, P> Clone for Node<S, P> {
+impl<S> Clone for Node<S> {
     fn clone(&self) -> Self {
         Self {
             inner: self.inner.clone(),
@@ -109,93 +290,286 @@ impl<S, P> Clone for Node<S, P> {
     }
 }
 
-impl<S, P> Deref for Node<S, P> {
-    type Target = Arc<NodeInner<S, P>>;
+impl<S> Deref for Node<S> {
+    type Target = Arc<NodeInner<S>>;
 
     fn deref(&self) -> &Self::Target {
         &self.inner
     }
 }
 
-impl<S, P> Node<S, P>
+impl<S> Node<S>
 where
     S: Send + Sync + 'static,
-    P: Serialize + DeserializeOwned + Clone + Send + 'static,
 {
     async fn serve(
         self,
-        mut message_channel_rx: Receiver<String>,
-        tasks: Vec<Task<S, P>>,
+        mut input: Input,
+        output: Output,
+        message_channel_rx: Receiver<String>,
+        tasks: Vec<Task<S>>,
+        shutdown_hooks: Vec<ShutdownHook<S>>,
+        drain_timeout: Duration,
     ) -> Result<()> {
-        let mut set = JoinSet::new();
+        let (close_tx, close_rx) = oneshot::channel::<()>();
 
-        set.spawn(async move {
-            while let Some(message) = message_channel_rx.recv().await {
-                let mut stdout = io::stdout().lock();
+        let writer = tokio::spawn(write_messages(output, message_channel_rx, close_rx).in_current_span());
 
-                stdout.write_all(message.as_bytes())?;
-                stdout.write_all(b"\n")?;
-            }
-
-            Ok::<_, Report>(())
-        });
+        let mut task_set = JoinSet::new();
 
-        for task in tasks {
+        for (index, task) in tasks.into_iter().enumerate() {
             let node = self.clone();
-            set.spawn(async move {
+            task_set.spawn(async move {
+                // Background tasks start straight away
+                let mut delay = task.period.unwrap_or(Duration::ZERO);
+
                 loop {
-                    tokio::time::sleep(task.period).await;
-                    (task.handler)(node.clone()).await?;
+                    tokio::select! {
+                        biased;
+                        _ = node.shutdown.cancelled() => break,
+                        _ = tokio::time::sleep(delay) => {},
+                    }
+
+                    let span = debug_span!("task", task = index);
+                    let result = AssertUnwindSafe((task.handler)(node.clone()))
+                        .catch_unwind()
+                        .instrument(span.clone())
+                        .await
+                        .unwrap_or_else(|panic| Err(node.recover_from_panic("Task", panic)));
+
+                    // A failed tick is retried on the next period, and a failed background task started again, instead
+                    // of stopping the task
+                    match result {
+                        Ok(()) if task.period.is_none() => break,
+                        Ok(()) => {},
+                        Err(err) if node.failure_policy == FailurePolicy::Crash => {
+                            return Err(err.wrap_err(format!("Task {index} failed")));
+                        },
+                        Err(err) => span.in_scope(|| warn!("Task failed: {err:#}")),
+                    }
+
+                    delay = task.period.unwrap_or(BACKGROUND_TASK_RESTART_DELAY);
                 }
 
-                #[allow(unreachable_code)]
                 Ok::<_, Report>(())
-            });
+            }.in_current_span());
         }
 
-        let stdin = io::stdin();
-        let mut line = String::new();
+        let mut set = JoinSet::new();
+        let mut input_open = true;
+        // Set once shutdown starts: from then on input is only read for replies to the calls of in-flight
+        // handlers and tasks, until they are all done or the deadline passes
+        let mut deadline = None;
+
+        loop {
+            if deadline.is_none() && (self.shutdown.is_cancelled() || !input_open) {
+                info!("Shutting down");
+                self.shutdown.cancel();
+                deadline = Some(Instant::now() + drain_timeout);
+            }
+
+            if deadline.is_some() && set.is_empty() && task_set.is_empty() {
+                break;
+            }
+
+            // Branches are polled in order rather than at random, so that a simulated run always plays out the same way
+            let line = tokio::select! {
+                biased;
+                _ = self.shutdown.cancelled(), if deadline.is_none() => continue,
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    warn!("Shutdown drain period elapsed, abandoning {} handlers and {} tasks", set.len(), task_set.len());
+                    set.abort_all();
+                    task_set.abort_all();
+                    break;
+                },
+                // Finished handlers and tasks are reaped as they go so that a crash stops the node right away
+                Some(res) = set.join_next() => {
+                    res??;
+                    continue;
+                },
+                Some(res) = task_set.join_next() => {
+                    res??;
+                    continue;
+                },
+                line = next_line(&mut input), if input_open => match line? {
+                    Some(line) => line,
+                    None => {
+                        input_open = false;
+                        continue;
+                    },
+                },
+            };
+
+            let value = match serde_json::from_str::<serde_json::Value>(&line) {
+                Ok(value) => value,
+                Err(err) => {
+                    warn!("Skipping malformed message {line:?}: {err}");
+                    continue;
+                },
+            };
+            let span = inbound_span(&value);
+
+            // Messages between nodes are counted by their sender
+            if !self.stats.is_server(value["src"].as_str().unwrap_or_default()) {
+                self.stats.record_message(&value);
+            }
+
+            // Replies to outstanding rpc and service calls are routed to the waiting future instead of the handler
+            let in_reply_to = value.get("body")
+                .and_then(|body| body.get("in_reply_to"))
+                .and_then(|in_reply_to| in_reply_to.as_i64());
 
-        while let Ok(_) = stdin.read_line(&mut line) {
-            let value = serde_json::from_str::<serde_json::Value>(&line)?;
-            line.clear();
+            // An id out of range can't belong to any call we made, rather than to whichever one it truncates to
+            let value = match in_reply_to.and_then(|in_reply_to| i32::try_from(in_reply_to).ok()) {
+                Some(in_reply_to) => match self.outbox.resolve(in_reply_to, value) {
+                    Ok(()) => {
+                        span.in_scope(|| debug!("Routed reply to pending call"));
+                        continue;
+                    },
+                    Err(value) => value,
+                },
+                None => value,
+            };
+
+            if deadline.is_some() {
+                span.in_scope(|| debug!("Dropped request received while shutting down"));
+                continue;
+            }
 
-            if value.get("src").ok_or_eyre("Missing dest field")?.as_str().ok_or_eyre("src field is not a string")? == SEQUENTIAL_KV_STORE_ID {
-                let seq_kv_reply = serde_json::from_value::<Message<SequentialKVStorePayload>>(value)?;
-                self.seq_kv.handle_reply(seq_kv_reply).await?;
+            let Some(src) = value.get("src").and_then(|src| src.as_str()) else {
+                span.in_scope(|| warn!("Skipping message without a string src"));
+                continue;
+            };
 
+            // A service reply nobody is waiting for belongs to a call that already timed out
+            if self.services.contains_key(src) {
+                span.in_scope(|| debug!("Dropped late reply from {src}"));
                 continue;
             }
 
-            let message = serde_json::from_value::<Message<P>>(value)?;
+            let message = match serde_json::from_value::<Message<serde_json::Value>>(value) {
+                Ok(message) => message,
+                Err(err) => {
+                    span.in_scope(|| warn!("Skipping malformed message: {err}"));
+                    continue;
+                },
+            };
+
             let node = self.clone();
+            let received_at = Instant::now();
 
             set.spawn(async move {
-                let reply = (node.handler)(node.clone(), message).await?;
+                debug!("Received message");
+                let request = message.without_payload();
+                let request_type = message.body.payload["type"].as_str().unwrap_or_default().to_string();
 
-                if let Some(reply) = reply {
-                    let reply = serde_json::to_string(&reply)?;
+                let service_request = Request {
+                    node: node.clone(),
+                    message,
+                };
 
-                    node.message_channel_tx.send(reply)
-                        .await
-                        .map_err(|_| eyre!("Failed to send message via message_channel"))?;
+                let response = AssertUnwindSafe(node.service.clone().oneshot(service_request))
+                    .catch_unwind()
+                    .await
+                    .unwrap_or_else(|panic| Err(node.recover_from_panic("Handler", panic)));
+
+                match response {
+                    Ok(Some(reply)) => {
+                        node.outbox.send(reply).await?;
+
+                        if !node.stats.is_server(&request.src) {
+                            node.stats.record_latency(&request_type, received_at.elapsed());
+                        }
+                    },
+                    Ok(None) => {},
+                    Err(err) => node.handle_failure(request, err).await?,
                 }
 
                 Ok::<_, Report>(())
-            });
+            }.instrument(span));
         }
 
-        while let Some(res) = set.join_next().await {
-            res??;
+        for hook in shutdown_hooks {
+            if let Err(err) = hook(self.clone()).await {
+                warn!("Shutdown hook failed: {err:#}");
+            }
         }
 
-        Ok(())
+        let _ = close_tx.send(());
+        info!(stats = ?self.outbound_stats(), "Outbound queue");
+
+        let stats = self.stats();
+        info!("Message stats: {stats}");
+
+        if let Some(path) = &self.stats_file {
+            let path = PathBuf::from(path.to_string_lossy().replace("{node_id}", &self.node_id));
+
+            if let Err(err) = write_stats(&path, &stats) {
+                warn!("Failed to write stats to {}: {err:#}", path.display());
+            }
+        }
+
+        // The hooks may have taken the rest of the drain period, so the writer gets one of its own for what they sent
+        match tokio::time::timeout(drain_timeout, writer).await {
+            Ok(res) => res?,
+            Err(_) => {
+                warn!("Shutdown drain period elapsed before all outbound messages were written");
+                Ok(())
+            },
+        }
     }
 
-    pub async fn send_new_message(&self, dest: String, payload: P) -> Result<()> {
+    /// Shuts the node down like a closed stdin would: new requests are no longer handled and tasks stop, while
+    /// in-flight handlers keep receiving replies to their calls until they finish or the drain period passes.
+    /// The `NodeServer::on_shutdown` hooks then run, and outbound messages, theirs included, are drained before
+    /// `serve` returns.
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+
+    /// A panic while the state lock is held poisons it, which would make every later handler panic too. The failure
+    /// policy decides whether the node carries on after a panic, so the lock is made usable again either way.
+    fn recover_from_panic(&self, what: &str, panic: Box<dyn Any + Send>) -> Report {
+        self.state.clear_poison();
+        eyre!("{what} panicked: {}", panic_message(panic))
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.is_cancelled()
+    }
+
+    pub fn outbound_stats(&self) -> OutboundStats {
+        self.outbox.stats()
+    }
+
+    /// Messages this node sent and received, and how long it took to answer client requests. Messages between
+    /// nodes are only counted by their sender, so the stats of all nodes add up to those of the whole cluster.
+    /// Their `msgs_per_op` only covers this node though: Maelstrom's figure needs the counts of every node added up
+    /// first, as `Simulator::stats` does.
+    pub fn stats(&self) -> StatsSummary {
+        self.stats.summary()
+    }
+
+    pub fn service(&self, service_id: &str) -> Option<&ServiceClient> {
+        self.services.get(service_id).map(|service| service.as_ref())
+    }
+
+    pub async fn rpc<Req, Resp>(&self, dest: NodeId, payload: Req) -> Result<Resp>
+    where
+        Req: Serialize + Clone,
+        Resp: DeserializeOwned,
+    {
+        self.rpc_with_options(dest, payload, &self.rpc_options).await
+    }
+
+    pub async fn rpc_with_options<Req, Resp>(&self, dest: NodeId, payload: Req, options: &RpcOptions) -> Result<Resp>
+    where
+        Req: Serialize + Clone,
+        Resp: DeserializeOwned,
+    {
         let message = Message {
             src: self.node_id.clone(),
-            dest,
+            dest: dest.clone(),
             body: MessageBody {
                 message_id: None,
                 in_reply_to: None,
@@ -203,84 +577,644 @@ where
             },
         };
 
-        let message = serde_json::to_string(&message)?;
-
-        self.message_channel_tx.send(message)
+        let reply = self.outbox.call(message, options)
             .await
-            .map_err(|_| eyre!("Failed to send message via message_channel"))?;
+            .map_err(|err| err.wrap_err(format!("rpc to {dest} failed")))?;
 
-        Ok(())
+        Ok(decode_reply::<Resp>(reply)?.body.payload)
+    }
+
+    async fn handle_failure(&self, request: Message<()>, err: Report) -> Result<()> {
+        // A `MaelstromError` is a deliberate answer rather than a failure, so it is always sent back
+        let err = match err.downcast::<MaelstromError>() {
+            Ok(error) => return self.send_error_reply(request, error).await,
+            Err(err) => err,
+        };
+
+        match self.failure_policy {
+            FailurePolicy::LogAndContinue => {
+                warn!("Failed to handle message from {}: {err:#}", request.src);
+                Ok(())
+            },
+            FailurePolicy::ReplyWithError => {
+                error!("Failed to handle message from {}: {err:#}", request.src);
+                self.send_error_reply(request, MaelstromError::crash(format!("{err:#}"))).await
+            },
+            FailurePolicy::Crash => Err(err.wrap_err(format!("Failed to handle message from {}", request.src))),
+        }
+    }
+
+    async fn send_error_reply(&self, request: Message<()>, error: MaelstromError) -> Result<()> {
+        // Only requests carry a msg_id, there is nobody to reply to otherwise
+        if request.body.message_id.is_none() {
+            warn!("Failed to handle message from {}: {error}", request.src);
+            return Ok(());
+        }
+
+        self.outbox.send(request.into_reply(error)).await
+    }
+
+    pub async fn send_new_message<P: Serialize>(&self, dest: String, payload: P) -> Result<()> {
+        let message = Message {
+            src: self.node_id.clone(),
+            dest,
+            body: MessageBody {
+                message_id: None,
+                in_reply_to: None,
+                payload,
+            },
+        };
+
+        self.outbox.send(message).await
     }
 }
 
-pub struct NodeServer<S, P> {
+type LayerFn<S> = Box<dyn FnOnce(HandlerService<S>) -> HandlerService<S> + Send>;
+
+pub struct NodeServer<S> {
     state: S,
-    handler: MessageHandler<S, P>,
-    tasks: Vec<Task<S, P>>,
+    router: Router<S>,
+    layers: Vec<LayerFn<S>>,
+    tasks: Vec<Task<S>>,
+    shutdown_hooks: Vec<ShutdownHook<S>>,
+    drain_timeout: Duration,
+    outbound_capacity: usize,
+    rpc_options: RpcOptions,
+    failure_policy: FailurePolicy,
+    seed: u64,
+    services: Vec<NodeId>,
+    stats_file: Option<PathBuf>,
 }
 
-impl<S, P> NodeServer<S, P>
+impl<S> NodeServer<S>
 where
     S: Send + Sync + 'static,
-    P: Serialize + DeserializeOwned + Clone + Send + 'static,
 {
-    pub fn new<H, Fut>(state: S, handler: H) -> Self
-    where
-        H: Fn(Node<S, P>, Message<P>) -> Fut + Send + Sync + 'static,
-        Fut: Future<Output = MessageReply<P>> + Send + 'static,
-    {
+    pub fn new(state: S, router: Router<S>) -> Self {
         Self {
             state,
-            handler: Box::new(move |node, message| Box::pin(handler(node, message))),
+            router,
+            layers: Vec::new(),
             tasks: Vec::new(),
+            shutdown_hooks: Vec::new(),
+            drain_timeout: Duration::from_secs(5),
+            outbound_capacity: DEFAULT_OUTBOUND_CAPACITY,
+            rpc_options: RpcOptions::default(),
+            failure_policy: FailurePolicy::default(),
+            seed: 0,
+            services: vec![
+                SEQUENTIAL_KV_STORE_ID.to_string(),
+                LINEARIZABLE_KV_STORE_ID.to_string(),
+                LWW_KV_STORE_ID.to_string(),
+                TIMESTAMP_ORACLE_ID.to_string(),
+            ],
+            stats_file: None,
         }
     }
 
     pub fn add_task<H, Fut>(mut self, task: H, period: Duration) -> Self
     where
-        H: Fn(Node<S, P>) -> Fut + Send + Sync + 'static,
+        H: Fn(Node<S>) -> Fut + Send + Sync + 'static,
         Fut: Future<Output = Result<()>> + Send + 'static,
     {
         self.tasks.push(Task {
             handler: Box::new(move |node| Box::pin(task(node))),
-            period,
+            period: Some(period),
         });
         self
     }
 
-    pub async fn serve(self) -> Result<()> {
-        color_eyre::install()?;
+    /// Runs `task` once, as soon as the node has started, for work that loops on its own rather than every period.
+    /// It should return once `Node::is_shutting_down`. If it fails or panics it is handled like a failed periodic
+    /// task: `FailurePolicy::Crash` stops the node, while otherwise the task is started again shortly after.
+    pub fn add_background_task<H, Fut>(mut self, task: H) -> Self
+    where
+        H: Fn(Node<S>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.tasks.push(Task {
+            handler: Box::new(move |node| Box::pin(task(node))),
+            period: None,
+        });
+        self
+    }
 
-        let payload = {
-            let mut stdin = io::stdin().lock();
-            let mut stdout = io::stdout().lock();
+    /// Runs `hook` once the node has stopped handling messages, e.g. to flush state. Hooks run in the order they
+    /// were added, without a time limit, and can still send messages, which are written before `serve` returns.
+    pub fn on_shutdown<H, Fut>(mut self, hook: H) -> Self
+    where
+        H: FnOnce(Node<S>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.shutdown_hooks.push(Box::new(move |node| Box::pin(hook(node))));
+        self
+    }
 
-            let mut line = String::new();
-            stdin.read_line(&mut line)?;
+    /// How long in-flight handlers and tasks get to finish once shutdown starts, and then how long outbound messages
+    /// get to be written once the shutdown hooks have run
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
 
-            let init_message = serde_json::from_str::<Message<Init>>(&line)?;
-            let (init_message, payload) = init_message.take_payload();
+    /// Wraps the router and every layer added before this one with a `tower::Layer`, so the last layer added
+    /// is the first to see each incoming message
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<HandlerService<S>> + Send + 'static,
+        L::Service: Service<Request<S>, Response = Response, Error = Report> + Clone + Send + Sync + 'static,
+        <L::Service as Service<Request<S>>>::Future: Send + 'static,
+    {
+        self.layers.push(Box::new(move |service| HandlerService::new(layer.layer(service))));
+        self
+    }
+
+    /// How many messages, at least one, can be queued for stdout before sending waits for the writer to catch up
+    pub fn outbound_capacity(mut self, capacity: usize) -> Self {
+        self.outbound_capacity = capacity.max(1);
+        self
+    }
 
-            let init_ok_message = init_message.into_reply(InitOk::default());
-            serde_json::to_writer(&mut stdout, &init_ok_message)?;
-            stdout.write_all(b"\n")?;
+    pub fn rpc_options(mut self, options: RpcOptions) -> Self {
+        self.rpc_options = options;
+        self
+    }
+
+    pub fn failure_policy(mut self, policy: FailurePolicy) -> Self {
+        self.failure_policy = policy;
+        self
+    }
 
-            payload
+    /// Seeds the jitter of RPC retries. Each node mixes in its id, so a cluster sharing one seed doesn't retry in
+    /// lockstep, while the same seed and node id always give the same delays.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Writes `Node::stats` to `path` as JSON at shutdown. They're logged to stderr either way. Any `{node_id}` in
+    /// the path is replaced with the node's id, so that the nodes of a cluster each get their own file.
+    pub fn stats_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.stats_file = Some(path.into());
+        self
+    }
+
+    /// Registers a node that is called through a `ServiceClient` (see `Node::service`) rather than handled by the router
+    pub fn add_service(mut self, service_id: impl Into<NodeId>) -> Self {
+        self.services.push(service_id.into());
+        self
+    }
+
+    /// Serves Maelstrom over stdin and stdout, or over sockets when `DSC_TRANSPORT` asks for them (see
+    /// `transport::SocketTransport`). Use `serve_with` to pick the transport in code instead.
+    ///
+    /// Setting `STATS_FILE`, e.g. to `stats-{node_id}.json`, has the same effect as `stats_file`.
+    pub async fn serve(mut self) -> Result<()> {
+        if let (None, Ok(path)) = (&self.stats_file, std::env::var("STATS_FILE")) {
+            self.stats_file = Some(path.into());
+        }
+
+        self.serve_connected(connect_from_env()).await
+    }
+
+    pub async fn serve_with(self, transport: impl Transport) -> Result<()> {
+        self.serve_connected(transport.connect()).await
+    }
+
+    async fn serve_connected(self, connect: impl Future<Output = Result<(Input, Output)>>) -> Result<()> {
+        color_eyre::install()?;
+
+        // Maelstrom keeps each node's stderr as its log. The filter is read from `RUST_LOG`, e.g. `RUST_LOG=debug`.
+        let _ = tracing_subscriber::registry()
+            .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+            .with(tracing_subscriber::fmt::layer().with_writer(io::stderr).with_ansi(false))
+            .with(ErrorLayer::default())
+            .try_init();
+
+        let (input, output) = connect.await?;
+        let shutdown = CancellationToken::new();
+
+        let signal = {
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                if terminated().await.is_ok() {
+                    shutdown.cancel();
+                }
+            })
         };
 
-        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        let result = self.serve_on(input, output, shutdown).await;
+
+        signal.abort();
+        result
+    }
+
+    /// Runs the node on `input` and `output`. Cancelling `shutdown` has the same effect as `Node::shutdown`.
+    pub(crate) async fn serve_on(self, mut input: Input, output: Output, shutdown: CancellationToken) -> Result<()> {
+        let line = next_line(&mut input).await?.ok_or_eyre("Input closed before init message")?;
+        let init_message = serde_json::from_str::<Message<Init>>(&line)?;
+        let (init_message, payload) = init_message.take_payload();
+        let span = info_span!("node", id = payload.node_id);
+        span.in_scope(|| info!(node_ids = ?payload.node_ids, "Initialised"));
+
+        let stats = Arc::new(Stats::new(payload.node_ids.iter().cloned()));
+        let (tx, rx) = tokio::sync::mpsc::channel(self.outbound_capacity);
+        let outbox = Arc::new(Outbox::new(tx, stats.clone(), mix_seed(self.seed, &payload.node_id)));
+
+        // Queued until the writer starts, ahead of anything the node sends
+        outbox.send(init_message.into_reply(InitOk::default())).await?;
+
+        let services = self.services.into_iter()
+            .map(|service_id| {
+                let client = ServiceClient::new(service_id.clone(), payload.node_id.clone(), outbox.clone(), self.rpc_options.clone());
+                (service_id, Arc::new(client))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let service = self.layers.into_iter()
+            .fold(HandlerService::new(RouterService::new(self.router)), |service, layer| layer(service));
 
         let node = Node {
             inner: Arc::new(NodeInner {
                 node_id: payload.node_id.clone(),
                 node_ids: payload.node_ids,
                 state: RwLock::new(self.state),
-                seq_kv: SequentialKVStore::new(payload.node_id, tx.clone()),
-                handler: self.handler,
-                message_channel_tx: tx,
+                seq_kv: KVStore::new(services[SEQUENTIAL_KV_STORE_ID].clone()),
+                lin_kv: KVStore::new(services[LINEARIZABLE_KV_STORE_ID].clone()),
+                lww_kv: KVStore::new(services[LWW_KV_STORE_ID].clone()),
+                lin_tso: TimestampOracle::new(services[TIMESTAMP_ORACLE_ID].clone()),
+                services,
+                service,
+                outbox,
+                rpc_options: self.rpc_options,
+                failure_policy: self.failure_policy,
+                shutdown,
+                stats,
+                stats_file: self.stats_file,
             }),
         };
 
-        node.serve(rx, self.tasks).await
+        node.serve(input, output, rx, self.tasks, self.shutdown_hooks, self.drain_timeout)
+            .instrument(span)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::task::{Context, Poll, Waker};
+
+    use futures::Sink;
+    use serde_json::{json, Value};
+    use crate::extract::{KvClient, State};
+    use crate::simulator::{NetworkConfig, Simulator};
+    use super::*;
+
+    async fn shut_down_then_write(node: Node<()>, kv: KvClient) -> Result<Reply<Value>> {
+        node.shutdown();
+        kv.write("key".to_string(), 1).await?;
+
+        Ok(Reply(json!({ "type": "write_ok" })))
+    }
+
+    async fn use_services(node: Node<()>) -> Result<Reply<Value>> {
+        let first = node.lin_tso.ts().await?;
+        let second = node.lin_tso.ts().await?;
+
+        node.seq_kv.write("key".to_string(), "seq").await?;
+        node.lin_kv.write("key".to_string(), "lin").await?;
+        node.lww_kv.write("key".to_string(), "lww").await?;
+
+        Ok(Reply(json!({
+            "type": "use_services_ok",
+            "timestamps": [first, second],
+            "seq_kv": node.seq_kv.read::<String>("key".to_string()).await?,
+            "lin_kv": node.lin_kv.read::<String>("key".to_string()).await?,
+            "lww_kv": node.lww_kv.read::<String>("key".to_string()).await?,
+        })))
+    }
+
+    async fn panic_holding_state(state: State<u32>) -> Reply<Value> {
+        let _state = state.write().unwrap();
+        panic!("Bad request");
+    }
+
+    async fn read_state(state: State<u32>) -> Reply<Value> {
+        Reply(json!({ "type": "read_ok", "value": *state.read().unwrap() }))
+    }
+
+    async fn relay(node: Node<()>) -> Result<Reply<Value>> {
+        let reply: Value = node.rpc("n1".to_string(), json!({ "type": "get" })).await?;
+        Ok(Reply(json!({ "type": "relay_ok", "value": reply["value"] })))
+    }
+
+    async fn fail() -> Result<Reply<Value>> {
+        Err(eyre!("Disk on fire"))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_node_talks_to_each_service() {
+        let simulator = Simulator::start(1, NetworkConfig::default(), || {
+            NodeServer::new((), Router::new().route("use_services", use_services))
+        }).await.unwrap();
+
+        let reply: Value = simulator.request("n0", json!({ "type": "use_services" })).await.unwrap();
+
+        let timestamps = reply["timestamps"].as_array().unwrap();
+        assert!(timestamps[0].as_u64() < timestamps[1].as_u64());
+
+        // Each store keeps its own copy of the key
+        assert_eq!(reply["seq_kv"], "seq");
+        assert_eq!(reply["lin_kv"], "lin");
+        assert_eq!(reply["lww_kv"], "lww");
+
+        simulator.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_panic_holding_the_state_lock_does_not_break_the_node() {
+        let simulator = Simulator::start(1, NetworkConfig::default(), || {
+            let router = Router::new()
+                .route("panic", panic_holding_state)
+                .route("read", read_state);
+
+            NodeServer::new(7, router).failure_policy(FailurePolicy::ReplyWithError)
+        }).await.unwrap();
+
+        let err = simulator.request::<_, Value>("n0", json!({ "type": "panic" })).await.unwrap_err();
+        assert_eq!(error_code(&err), Some(ErrorCode::Crash));
+
+        let reply: Value = simulator.request("n0", json!({ "type": "read" })).await.unwrap();
+        assert_eq!(reply["value"], 7);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_handlers_are_answered_with_a_crash() {
+        let simulator = Simulator::start(1, NetworkConfig::default(), || {
+            NodeServer::new((), Router::new().route("fail", fail)).failure_policy(FailurePolicy::ReplyWithError)
+        }).await.unwrap();
+
+        let err = simulator.request::<_, Value>("n0", json!({ "type": "fail" })).await.unwrap_err();
+        let error = err.downcast_ref::<MaelstromError>().unwrap();
+
+        assert_eq!(error.code, ErrorCode::Crash);
+        assert!(error.text.contains("Disk on fire"), "{}", error.text);
+
+        simulator.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn out_of_range_reply_ids_complete_no_call() {
+        let (input_tx, input_rx) = tokio::sync::mpsc::unbounded_channel::<Value>();
+        let input = futures::stream::unfold(input_rx, |mut rx| async move {
+            rx.recv().await.map(|line| (Ok(line.to_string()), rx))
+        }).boxed();
+
+        let (output_tx, mut output_rx) = tokio::sync::mpsc::unbounded_channel();
+        let output = futures::sink::unfold(output_tx, |tx, line: String| async move {
+            tx.send(serde_json::from_str::<Value>(&line)?)?;
+            Ok::<_, Report>(tx)
+        });
+
+        let server = NodeServer::new((), Router::new().route("relay", relay));
+        let node = tokio::spawn(server.serve_on(input, Box::pin(output), CancellationToken::new()));
+
+        input_tx.send(json!({ "src": "c0", "dest": "n0", "body": { "type": "init", "msg_id": 0, "node_id": "n0", "node_ids": ["n0", "n1"] } })).unwrap();
+        input_tx.send(json!({ "src": "c1", "dest": "n0", "body": { "type": "relay", "msg_id": 1 } })).unwrap();
+
+        assert_eq!(output_rx.recv().await.unwrap()["body"]["type"], "init_ok");
+        let request = output_rx.recv().await.unwrap();
+        assert_eq!(request["dest"], "n1");
+        let message_id = request["body"]["msg_id"].as_i64().unwrap();
+
+        // Truncated to 32 bits, this id would be the pending call's
+        let bogus = message_id + (1 << 32);
+        input_tx.send(json!({ "src": "n1", "dest": "n0", "body": { "type": "get_ok", "value": "bogus", "in_reply_to": bogus } })).unwrap();
+        input_tx.send(json!({ "src": "n1", "dest": "n0", "body": { "type": "get_ok", "value": "genuine", "in_reply_to": message_id } })).unwrap();
+
+        let reply = output_rx.recv().await.unwrap();
+        assert_eq!(reply["body"]["type"], "relay_ok");
+        assert_eq!(reply["body"]["value"], "genuine");
+
+        drop(input_tx);
+        node.await.unwrap().unwrap();
+    }
+
+    /// Counts its runs, failing the first one
+    async fn count_and_fail_once(node: Node<u32>) -> Result<()> {
+        let mut runs = node.state.write().unwrap();
+        *runs += 1;
+
+        if *runs == 1 {
+            return Err(eyre!("First run"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_failed_periodic_task_runs_again_next_period() {
+        let simulator = Simulator::start(1, NetworkConfig::default(), || {
+            NodeServer::new(0, Router::new().route("read", read_state))
+                .add_task(count_and_fail_once, Duration::from_millis(100))
+                .failure_policy(FailurePolicy::LogAndContinue)
+        }).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(350)).await;
+
+        let reply: Value = simulator.request("n0", json!({ "type": "read" })).await.unwrap();
+        assert_eq!(reply["value"], 3);
+
+        simulator.shutdown().await.unwrap();
+    }
+
+    /// Fails the first time it runs, then runs until the node shuts down
+    async fn fail_then_run(node: Node<u32>) -> Result<()> {
+        let runs = {
+            let mut runs = node.state.write().unwrap();
+            *runs += 1;
+            *runs
+        };
+
+        if runs == 1 {
+            return Err(eyre!("First run"));
+        }
+
+        while !node.is_shutting_down() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_failed_background_task_is_started_again() {
+        let simulator = Simulator::start(1, NetworkConfig::default(), || {
+            NodeServer::new(0, Router::new().route("read", read_state))
+                .add_background_task(fail_then_run)
+                .failure_policy(FailurePolicy::LogAndContinue)
+        }).await.unwrap();
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        // Started once more after failing, and not again while it runs
+        let reply: Value = simulator.request("n0", json!({ "type": "read" })).await.unwrap();
+        assert_eq!(reply["value"], 2);
+
+        simulator.shutdown().await.unwrap();
+    }
+
+    async fn sleep_forever() -> Reply<Value> {
+        tokio::time::sleep(Duration::from_secs(3600)).await;
+        Reply(json!({ "type": "sleep_ok" }))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn messages_from_shutdown_hooks_are_written_after_the_drain_period() {
+        let init = json!({ "src": "c0", "dest": "n0", "body": { "type": "init", "msg_id": 0, "node_id": "n0", "node_ids": ["n0"] } });
+        let sleep = json!({ "src": "c1", "dest": "n0", "body": { "type": "sleep", "msg_id": 1 } });
+        let input = futures::stream::iter([Ok(init.to_string()), Ok(sleep.to_string())]).boxed();
+
+        let (output_tx, mut output_rx) = tokio::sync::mpsc::unbounded_channel();
+        let output = futures::sink::unfold(output_tx, |tx, line: String| async move {
+            tx.send(serde_json::from_str::<Value>(&line)?)?;
+            Ok::<_, Report>(tx)
+        });
+
+        // The handler outlasts the drain period, so it has run out by the time the hook runs
+        let server = NodeServer::new((), Router::new().route("sleep", sleep_forever))
+            .drain_timeout(Duration::from_millis(50))
+            .on_shutdown(|node: Node<()>| async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                node.send_new_message("c1".to_string(), json!({ "type": "goodbye" })).await
+            });
+
+        server.serve_on(input, Box::pin(output), CancellationToken::new()).await.unwrap();
+
+        let types = std::iter::from_fn(|| output_rx.try_recv().ok())
+            .map(|message| message["body"]["type"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(types, [json!("init_ok"), json!("goodbye")]);
+    }
+
+    /// An output that stalls until opened, and keeps what each flush wrote
+    #[derive(Clone, Default)]
+    struct GatedOutput {
+        open: Arc<AtomicBool>,
+        waker: Arc<Mutex<Option<Waker>>>,
+        unflushed: Arc<Mutex<Vec<String>>>,
+        flushes: Arc<Mutex<Vec<Vec<String>>>>,
+    }
+
+    impl GatedOutput {
+        fn open(&self) {
+            self.open.store(true, Ordering::SeqCst);
+
+            if let Some(waker) = self.waker.lock().unwrap().take() {
+                waker.wake();
+            }
+        }
+    }
+
+    impl Sink<String> for GatedOutput {
+        type Error = Report;
+
+        fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+            if self.open.load(Ordering::SeqCst) {
+                return Poll::Ready(Ok(()));
+            }
+
+            *self.waker.lock().unwrap() = Some(cx.waker().clone());
+            Poll::Pending
+        }
+
+        fn start_send(self: Pin<&mut Self>, line: String) -> Result<()> {
+            self.unflushed.lock().unwrap().push(line);
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+            let lines = std::mem::take(&mut *self.unflushed.lock().unwrap());
+
+            if !lines.is_empty() {
+                self.flushes.lock().unwrap().push(lines);
+            }
+
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+            self.poll_flush(cx)
+        }
+    }
+
+    fn message(echo: &str) -> Message<Value> {
+        Message {
+            src: "n0".to_string(),
+            dest: "c1".to_string(),
+            body: MessageBody {
+                message_id: None,
+                in_reply_to: None,
+                payload: json!({ "type": "echo_ok", "echo": echo }),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn a_stalled_writer_blocks_senders_and_then_writes_the_backlog_at_once() {
+        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        let outbox = Arc::new(Outbox::new(tx, Arc::new(Stats::new(["n0".to_string()])), 0));
+
+        let output = GatedOutput::default();
+        let (close_tx, close_rx) = oneshot::channel();
+        let writer = tokio::spawn(write_messages(Box::pin(output.clone()), rx, close_rx));
+
+        // The writer takes the first message and stalls on it, the second fills the queue
+        outbox.send(message("a")).await.unwrap();
+        tokio::task::yield_now().await;
+        outbox.send(message("b")).await.unwrap();
+
+        let sender = tokio::spawn({
+            let outbox = outbox.clone();
+            async move { outbox.send(message("c")).await }
+        });
+        tokio::task::yield_now().await;
+
+        let stats = outbox.stats();
+        assert_eq!(stats.capacity, 1);
+        assert_eq!(stats.queue_depth, 1);
+        assert_eq!(stats.blocked_sends, 1);
+        assert_eq!(stats.sent, 2);
+
+        output.open();
+        sender.await.unwrap().unwrap();
+        let _ = close_tx.send(());
+        writer.await.unwrap().unwrap();
+
+        let flushes = output.flushes.lock().unwrap().iter()
+            .map(|lines| lines.iter().map(|line| serde_json::from_str::<Value>(line).unwrap()["body"]["echo"].clone()).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        // What the writer stalled on goes out together with what queued up behind it
+        assert_eq!(flushes, [vec![json!("a"), json!("b")], vec![json!("c")]]);
+        assert_eq!(outbox.stats().sent, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn draining_handlers_still_get_their_replies() {
+        let simulator = Simulator::start(1, NetworkConfig::default(), || {
+            NodeServer::new((), Router::new().route("write", shut_down_then_write))
+        }).await.unwrap();
+
+        let reply: Value = simulator.request("n0", json!({ "type": "write" })).await.unwrap();
+        assert_eq!(reply["type"], "write_ok");
+
+        // Requests that arrive once shutdown has started are no longer handled
+        assert!(simulator.request::<_, Value>("n0", json!({ "type": "write" })).await.is_err());
+
+        simulator.shutdown().await.unwrap();
     }
 }
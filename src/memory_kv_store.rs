@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use color_eyre::Result;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::error::MaelstromError;
+use crate::kv_store::{CasOutcome, KeyValueStore};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Consistency {
+    /// Every operation observes the latest write, like `lin-kv`
+    Linearizable,
+    /// Reads may return any value at least as recent as the last one this session observed, like `seq-kv`
+    Sequential,
+    /// Writes are ordered by skewed per-session clocks so a later write can be silently lost, like `lww-kv`
+    LastWriteWins,
+}
+
+/// In-process key-value store for exercising handler logic without Maelstrom. Cloning a store shares the data
+/// but not the session, so each clone behaves like a separate client of the same service.
+pub struct MemoryKVStore {
+    consistency: Consistency,
+    shared: Arc<Mutex<SharedState>>,
+    session: Mutex<Session>,
+}
+
+struct SharedState {
+    keys: HashMap<String, Versions>,
+    clock: u64,
+    rng: StdRng,
+}
+
+#[derive(Default)]
+struct Versions {
+    // Every value ever written to the key along with the timestamp it was written with, oldest first
+    values: Vec<(u64, String)>,
+}
+
+impl Versions {
+    /// Index of the value a fresh read would return, ignoring any staleness
+    fn current(&self, consistency: Consistency) -> Option<usize> {
+        match consistency {
+            // The winning value is the one with the highest timestamp, which isn't necessarily the last written
+            Consistency::LastWriteWins => self.values.iter()
+                .enumerate()
+                .max_by_key(|(index, (timestamp, _))| (*timestamp, *index))
+                .map(|(index, _)| index),
+            Consistency::Linearizable | Consistency::Sequential => self.values.len().checked_sub(1),
+        }
+    }
+}
+
+struct Session {
+    // Index of the most recent version of each key this session has observed
+    observed: HashMap<String, usize>,
+    clock_skew: u64,
+}
+
+impl MemoryKVStore {
+    pub fn new(consistency: Consistency) -> Self {
+        Self::with_rng(consistency, StdRng::from_entropy())
+    }
+
+    pub fn seeded(consistency: Consistency, seed: u64) -> Self {
+        Self::with_rng(consistency, StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(consistency: Consistency, mut rng: StdRng) -> Self {
+        let session = Session::new(&mut rng);
+
+        Self {
+            consistency,
+            shared: Arc::new(Mutex::new(SharedState {
+                keys: HashMap::new(),
+                clock: 0,
+                rng,
+            })),
+            session: Mutex::new(session),
+        }
+    }
+
+    pub fn consistency(&self) -> Consistency {
+        self.consistency
+    }
+
+    fn read_value(&self, key: &str) -> Result<String> {
+        let mut shared = self.shared.lock().unwrap();
+        let mut session = self.session.lock().unwrap();
+        let shared = &mut *shared;
+
+        let versions = shared.keys.get(key);
+        let current = versions.and_then(|versions| versions.current(self.consistency));
+
+        let (Some(versions), Some(current)) = (versions, current) else {
+            return Err(MaelstromError::key_does_not_exist(format!("Key {key} does not exist")).into());
+        };
+
+        let index = match self.consistency {
+            Consistency::Sequential => {
+                let observed = session.observed.get(key).copied().unwrap_or(0);
+                shared.rng.gen_range(observed..=current)
+            },
+            Consistency::Linearizable | Consistency::LastWriteWins => current,
+        };
+
+        session.observed.insert(key.to_string(), index);
+        Ok(versions.values[index].1.clone())
+    }
+
+    fn write_value(&self, key: String, value: String) {
+        let mut shared = self.shared.lock().unwrap();
+        let mut session = self.session.lock().unwrap();
+
+        shared.push_version(&mut session, key, value);
+    }
+
+    fn cas_value<D: DeserializeOwned>(&self, key: String, from: Option<String>, to: String, create_if_not_exists: bool) -> Result<CasOutcome<D>> {
+        let mut shared = self.shared.lock().unwrap();
+        let mut session = self.session.lock().unwrap();
+
+        let current = shared.keys.get(&key)
+            .and_then(|versions| Some(&versions.values[versions.current(self.consistency)?].1));
+
+        match current {
+            Some(current) if Some(current) != from.as_ref() => {
+                return Ok(CasOutcome::PreconditionFailed { current: serde_json::from_str(current)? });
+            },
+            None if !create_if_not_exists => return Ok(CasOutcome::KeyMissing),
+            _ => {},
+        }
+
+        shared.push_version(&mut session, key, to);
+        Ok(CasOutcome::Ok)
+    }
+}
+
+impl SharedState {
+    fn push_version(&mut self, session: &mut Session, key: String, value: String) {
+        self.clock += 1;
+        let timestamp = self.clock + session.clock_skew;
+
+        let versions = self.keys.entry(key.clone()).or_default();
+        versions.values.push((timestamp, value));
+        session.observed.insert(key, versions.values.len() - 1);
+    }
+}
+
+impl Clone for MemoryKVStore {
+    fn clone(&self) -> Self {
+        let session = Session::new(&mut self.shared.lock().unwrap().rng);
+
+        Self {
+            consistency: self.consistency,
+            shared: self.shared.clone(),
+            session: Mutex::new(session),
+        }
+    }
+}
+
+impl Session {
+    fn new(rng: &mut StdRng) -> Self {
+        Self {
+            observed: HashMap::new(),
+            clock_skew: rng.gen_range(0..16),
+        }
+    }
+}
+
+impl KeyValueStore for MemoryKVStore {
+    async fn read<D: DeserializeOwned>(&self, key: String) -> Result<D> {
+        Ok(serde_json::from_str(&self.read_value(&key)?)?)
+    }
+
+    async fn write<S: Serialize + Send>(&self, key: String, value: S) -> Result<()> {
+        self.write_value(key, serde_json::to_string(&value)?);
+        Ok(())
+    }
+
+    async fn cas_with<D>(&self, key: String, from: D, to: D, create_if_not_exists: bool) -> Result<CasOutcome<D>>
+    where
+        D: Serialize + DeserializeOwned + Send,
+    {
+        let from = serde_json::to_string(&from)?;
+        let to = serde_json::to_string(&to)?;
+
+        self.cas_value(key, Some(from), to, create_if_not_exists)
+    }
+
+    async fn create<D>(&self, key: String, value: D) -> Result<CasOutcome<D>>
+    where
+        D: Serialize + DeserializeOwned + Send,
+    {
+        self.cas_value(key, None, serde_json::to_string(&value)?, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::{error_code, ErrorCode};
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_its_own_writes() {
+        let kv = MemoryKVStore::seeded(Consistency::Linearizable, 1);
+
+        kv.write("a".to_string(), 1).await.unwrap();
+        kv.write("a".to_string(), 2).await.unwrap();
+
+        assert_eq!(kv.read::<i32>("a".to_string()).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn missing_keys_do_not_exist() {
+        let kv = MemoryKVStore::seeded(Consistency::Linearizable, 1);
+        let err = kv.read::<i32>("a".to_string()).await.unwrap_err();
+
+        assert_eq!(error_code(&err), Some(ErrorCode::KeyDoesNotExist));
+    }
+
+    #[tokio::test]
+    async fn cas_only_swaps_the_expected_value() {
+        let kv = MemoryKVStore::seeded(Consistency::Linearizable, 1);
+        kv.write("a".to_string(), 1).await.unwrap();
+
+        assert_eq!(kv.cas_with("a".to_string(), 1, 2, false).await.unwrap(), CasOutcome::Ok);
+        assert_eq!(kv.cas_with("a".to_string(), 1, 3, false).await.unwrap(), CasOutcome::PreconditionFailed { current: 2 });
+
+        let err = kv.cas("a".to_string(), 1, 3).await.unwrap_err();
+        assert_eq!(error_code(&err), Some(ErrorCode::PreconditionFailed));
+        assert_eq!(kv.read::<i32>("a".to_string()).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn cas_creates_missing_keys_only_when_asked() {
+        let kv = MemoryKVStore::seeded(Consistency::Linearizable, 1);
+
+        assert_eq!(kv.cas_with("a".to_string(), 0, 1, false).await.unwrap(), CasOutcome::KeyMissing);
+        assert_eq!(kv.cas_with("a".to_string(), 0, 1, true).await.unwrap(), CasOutcome::Ok);
+        assert_eq!(kv.read::<i32>("a".to_string()).await.unwrap(), 1);
+
+        assert_eq!(kv.create("a".to_string(), 5).await.unwrap(), CasOutcome::PreconditionFailed { current: 1 });
+        assert_eq!(kv.create("b".to_string(), 5).await.unwrap(), CasOutcome::Ok);
+    }
+
+    #[tokio::test]
+    async fn sequential_reads_can_be_stale_but_never_go_backwards() {
+        let writer = MemoryKVStore::seeded(Consistency::Sequential, 1);
+        let reader = writer.clone();
+
+        for value in 0..100 {
+            writer.write("a".to_string(), value).await.unwrap();
+        }
+
+        let mut reads = Vec::new();
+
+        for _ in 0..20 {
+            reads.push(reader.read::<i32>("a".to_string()).await.unwrap());
+        }
+
+        assert!(reads.iter().any(|value| *value < 99), "no stale read in {reads:?}");
+        assert!(reads.windows(2).all(|pair| pair[0] <= pair[1]), "reads went backwards: {reads:?}");
+
+        // The writer's own session always sees its last write
+        assert_eq!(writer.read::<i32>("a".to_string()).await.unwrap(), 99);
+    }
+}
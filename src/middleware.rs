@@ -0,0 +1,667 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ops::Range;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use color_eyre::Report;
+use futures::future::BoxFuture;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use tokio::time::Instant;
+use tower::{Layer, Service};
+use tower::util::BoxCloneSyncService;
+use tracing::{info, warn};
+use crate::{Message, Node, NodeId};
+use crate::error::{ErrorCode, MaelstromError};
+use crate::router::Router;
+use crate::stats::{LatencySummary, Stats};
+
+/// An incoming message on its way to the router, as seen by middleware
+pub struct Request<S> {
+    pub node: Node<S>,
+    pub message: Message<serde_json::Value>,
+}
+
+impl<S> Request<S> {
+    pub fn message_type(&self) -> &str {
+        self.message.body.payload.get("type")
+            .and_then(|message_type| message_type.as_str())
+            .unwrap_or_default()
+    }
+}
+
+/// The reply to send back, if any. Errors are turned into Maelstrom `error` replies by the node.
+pub type Response = Option<Message<serde_json::Value>>;
+
+/// The type-erased handler stack that layers added with `NodeServer::layer` wrap
+pub type HandlerService<S> = BoxCloneSyncService<Request<S>, Response, Report>;
+
+pub(crate) struct RouterService<S> {
+    router: Arc<Router<S>>,
+}
+
+impl<S> RouterService<S> {
+    pub fn new(router: Router<S>) -> Self {
+        Self {
+            router: Arc::new(router),
+        }
+    }
+}
+
+impl<S> Clone for RouterService<S> {
+    fn clone(&self) -> Self {
+        Self {
+            router: self.router.clone(),
+        }
+    }
+}
+
+impl<S> Service<Request<S>> for RouterService<S>
+where
+    S: Send + Sync + 'static,
+{
+    type Response = Response;
+    type Error = Report;
+    type Future = BoxFuture<'static, Result<Response, Report>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<S>) -> Self::Future {
+        let router = self.router.clone();
+        Box::pin(async move { router.handle(request.node, request.message).await })
+    }
+}
+
+/// Logs every message with its outcome and handling time
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LogLayer;
+
+impl<T> Layer<T> for LogLayer {
+    type Service = Log<T>;
+
+    fn layer(&self, inner: T) -> Self::Service {
+        Log { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct Log<T> {
+    inner: T,
+}
+
+impl<S, T> Service<Request<S>> for Log<T>
+where
+    T: Service<Request<S>, Response = Response, Error = Report>,
+    T::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Report;
+    type Future = BoxFuture<'static, Result<Response, Report>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<S>) -> Self::Future {
+        let description = format!(
+            "{} from {} (msg_id {:?})",
+            request.message_type(),
+            request.message.src,
+            request.message.body.message_id,
+        );
+        let start = Instant::now();
+        let response = self.inner.call(request);
+
+        Box::pin(async move {
+            let response = response.await;
+
+            match &response {
+                Ok(_) => info!(elapsed = ?start.elapsed(), "Handled {description}"),
+                Err(err) => warn!(elapsed = ?start.elapsed(), "Failed to handle {description}: {err:#}"),
+            }
+
+            response
+        })
+    }
+}
+
+/// Holds every message back for a random duration in `delay`, to exercise handlers under slow delivery. The
+/// durations come from a seeded rng, so they're the same every run.
+#[derive(Clone, Debug)]
+pub struct DelayLayer {
+    delay: Range<Duration>,
+    seed: u64,
+}
+
+impl DelayLayer {
+    pub fn new(delay: Range<Duration>) -> Self {
+        Self {
+            delay,
+            seed: 0,
+        }
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+
+impl<T> Layer<T> for DelayLayer {
+    type Service = Delay<T>;
+
+    fn layer(&self, inner: T) -> Self::Service {
+        Delay {
+            inner,
+            delay: self.delay.clone(),
+            rng: Arc::new(Mutex::new(StdRng::seed_from_u64(self.seed))),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Delay<T> {
+    inner: T,
+    delay: Range<Duration>,
+    rng: Arc<Mutex<StdRng>>,
+}
+
+impl<S, T> Service<Request<S>> for Delay<T>
+where
+    S: Send + Sync + 'static,
+    T: Service<Request<S>, Response = Response, Error = Report> + Clone + Send + 'static,
+    T::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Report;
+    type Future = BoxFuture<'static, Result<Response, Report>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<S>) -> Self::Future {
+        let delay = if self.delay.is_empty() {
+            self.delay.start
+        } else {
+            self.rng.lock().unwrap().gen_range(self.delay.clone())
+        };
+
+        // The inner service is only driven to readiness once the delay has passed
+        let mut inner = self.inner.clone();
+
+        Box::pin(async move {
+            tokio::time::sleep(delay).await;
+            futures::future::poll_fn(|cx| inner.poll_ready(cx)).await?;
+            inner.call(request).await
+        })
+    }
+}
+
+/// Answers a redelivered request (same `src` and `msg_id`) with the reply cached for the first delivery
+/// instead of running the handler again. `Node::rpc` keeps the `msg_id` of a request across its retries, so those
+/// are caught too. Errors with a definite code are cached like replies, since running the handler again could
+/// change the outcome. Remembers the most recent `capacity` requests, and at least one.
+#[derive(Clone, Debug)]
+pub struct DeduplicateLayer {
+    capacity: usize,
+}
+
+impl DeduplicateLayer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+        }
+    }
+}
+
+impl<T> Layer<T> for DeduplicateLayer {
+    type Service = Deduplicate<T>;
+
+    fn layer(&self, inner: T) -> Self::Service {
+        Deduplicate {
+            inner,
+            seen: Arc::new(Mutex::new(SeenRequests {
+                replies: HashMap::new(),
+                order: VecDeque::new(),
+                capacity: self.capacity,
+            })),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Deduplicate<T> {
+    inner: T,
+    seen: Arc<Mutex<SeenRequests>>,
+}
+
+struct SeenRequests {
+    // `None` while the first delivery is still being handled
+    replies: HashMap<(NodeId, i32), Option<Result<Response, MaelstromError>>>,
+    order: VecDeque<(NodeId, i32)>,
+    capacity: usize,
+}
+
+impl<S, T> Service<Request<S>> for Deduplicate<T>
+where
+    T: Service<Request<S>, Response = Response, Error = Report>,
+    T::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Report;
+    type Future = BoxFuture<'static, Result<Response, Report>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<S>) -> Self::Future {
+        let Some(message_id) = request.message.body.message_id else {
+            return Box::pin(self.inner.call(request));
+        };
+
+        let key = (request.message.src.clone(), message_id);
+
+        {
+            let mut seen = self.seen.lock().unwrap();
+
+            match seen.replies.get(&key) {
+                // A duplicate of a request still in flight is dropped, the original will be answered
+                Some(reply) => {
+                    let reply = match reply.clone() {
+                        Some(Ok(reply)) => Ok(reply),
+                        Some(Err(error)) => Err(error.into()),
+                        None => Ok(None),
+                    };
+
+                    return Box::pin(async move { reply });
+                },
+                None => seen.insert(key.clone()),
+            }
+        }
+
+        let mut in_flight = InFlight {
+            seen: self.seen.clone(),
+            key: Some(key),
+        };
+        let response = self.inner.call(request);
+
+        Box::pin(async move {
+            let response = response.await;
+
+            match &response {
+                Ok(reply) => in_flight.complete(Ok(reply.clone())),
+                Err(err) => match err.downcast_ref::<MaelstromError>() {
+                    Some(error) if error.code.is_definite() => in_flight.complete(Err(error.clone())),
+                    _ => {},
+                },
+            }
+
+            response
+        })
+    }
+}
+
+/// A request being handled for the first time. Unless it completes with a reply or a definite error, e.g. because
+/// the handler crashed, panicked or was abandoned, it is forgotten so that a retry gets handled again instead of
+/// going unanswered.
+struct InFlight {
+    seen: Arc<Mutex<SeenRequests>>,
+    key: Option<(NodeId, i32)>,
+}
+
+impl InFlight {
+    fn complete(&mut self, reply: Result<Response, MaelstromError>) {
+        let Some(key) = self.key.take() else {
+            return;
+        };
+
+        if let Some(entry) = self.seen.lock().unwrap().replies.get_mut(&key) {
+            *entry = Some(reply);
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.seen.lock().unwrap_or_else(PoisonError::into_inner).forget(&key);
+        }
+    }
+}
+
+impl SeenRequests {
+    fn insert(&mut self, key: (NodeId, i32)) {
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.replies.remove(&oldest);
+            }
+        }
+
+        self.order.push_back(key.clone());
+        self.replies.insert(key, None);
+    }
+
+    fn forget(&mut self, key: &(NodeId, i32)) {
+        self.replies.remove(key);
+        self.order.retain(|other| other != key);
+    }
+}
+
+/// Turns requests away with a `temporarily-unavailable` error once more than `burst` arrive faster than `rate` per
+/// second, counting every message that reaches the handlers
+#[derive(Clone, Debug)]
+pub struct RateLimitLayer {
+    rate: f64,
+    burst: f64,
+}
+
+impl RateLimitLayer {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate: if rate.is_finite() { rate.max(0.0) } else { 0.0 },
+            burst: burst.max(1) as f64,
+        }
+    }
+}
+
+impl<T> Layer<T> for RateLimitLayer {
+    type Service = RateLimit<T>;
+
+    fn layer(&self, inner: T) -> Self::Service {
+        RateLimit {
+            inner,
+            bucket: Arc::new(Mutex::new(TokenBucket {
+                rate: self.rate,
+                burst: self.burst,
+                tokens: self.burst,
+                refilled_at: Instant::now(),
+            })),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimit<T> {
+    inner: T,
+    bucket: Arc<Mutex<TokenBucket>>,
+}
+
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+
+        self.tokens = (self.tokens + (now - self.refilled_at).as_secs_f64() * self.rate).min(self.burst);
+        self.refilled_at = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+impl<S, T> Service<Request<S>> for RateLimit<T>
+where
+    T: Service<Request<S>, Response = Response, Error = Report>,
+    T::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Report;
+    type Future = BoxFuture<'static, Result<Response, Report>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<S>) -> Self::Future {
+        if !self.bucket.lock().unwrap().try_take() {
+            let message_type = request.message_type().to_string();
+            return Box::pin(async move {
+                Err(MaelstromError::new(ErrorCode::TemporarilyUnavailable, format!("Rate limited {message_type}")).into())
+            });
+        }
+
+        Box::pin(self.inner.call(request))
+    }
+}
+
+/// Records how long handlers take, by message type. Keep a clone of the layer to read the histograms back.
+#[derive(Clone)]
+pub struct LatencyLayer {
+    stats: Arc<Stats>,
+}
+
+impl LatencyLayer {
+    pub fn new() -> Self {
+        Self {
+            stats: Arc::new(Stats::new([])),
+        }
+    }
+
+    pub fn latencies(&self) -> BTreeMap<String, LatencySummary> {
+        self.stats.summary().latency_by_type
+    }
+}
+
+impl Default for LatencyLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Layer<T> for LatencyLayer {
+    type Service = Latency<T>;
+
+    fn layer(&self, inner: T) -> Self::Service {
+        Latency {
+            inner,
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Latency<T> {
+    inner: T,
+    stats: Arc<Stats>,
+}
+
+impl<S, T> Service<Request<S>> for Latency<T>
+where
+    T: Service<Request<S>, Response = Response, Error = Report>,
+    T::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = Report;
+    type Future = BoxFuture<'static, Result<Response, Report>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<S>) -> Self::Future {
+        let message_type = request.message_type().to_string();
+        let stats = self.stats.clone();
+        let start = Instant::now();
+        let response = self.inner.call(request);
+
+        Box::pin(async move {
+            let response = response.await;
+            stats.record_latency(&message_type, start.elapsed());
+            response
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::Result;
+    use serde_json::{json, Value};
+    use crate::{FailurePolicy, NodeServer, Reply, RetryPolicy, RpcOptions};
+    use crate::extract::State;
+    use crate::handler::Handler;
+    use crate::simulator::{LinkFaults, NetworkConfig, Simulator};
+    use super::*;
+
+    /// Asks n1 to `count`, retrying every 100ms until it answers
+    async fn relay(node: Node<u32>) -> Result<Reply<Value>> {
+        let options = RpcOptions::default()
+            .timeout(Duration::from_millis(100))
+            .retry(RetryPolicy::fixed(Duration::from_millis(10), 5));
+
+        Ok(Reply(node.rpc_with_options("n1".to_string(), json!({ "type": "count" }), &options).await?))
+    }
+
+    /// Takes longer than a retry to answer
+    async fn count_slowly(state: State<u32>) -> Reply<Value> {
+        tokio::time::sleep(Duration::from_millis(250)).await;
+
+        let mut count = state.write().unwrap();
+        *count += 1;
+
+        Reply(json!({ "type": "count_ok", "count": *count }))
+    }
+
+    /// Panics the first time, without ever answering
+    async fn count_after_a_panic(state: State<u32>) -> Reply<Value> {
+        let count = {
+            let mut count = state.write().unwrap();
+            *count += 1;
+            *count
+        };
+
+        if count == 1 {
+            panic!("First request");
+        }
+
+        Reply(json!({ "type": "count_ok", "count": count }))
+    }
+
+    /// Fails its precondition, saying how often it has been asked
+    async fn count_and_fail(state: State<u32>) -> Result<Reply<Value>> {
+        let mut count = state.write().unwrap();
+        *count += 1;
+
+        Err(MaelstromError::precondition_failed(format!("Asked {} time(s)", *count)).into())
+    }
+
+    async fn start<H, T>(count: H) -> Simulator
+    where
+        H: Handler<T, u32> + Clone,
+    {
+        Simulator::start(2, NetworkConfig::default(), || {
+            let router = Router::new()
+                .route("relay", relay)
+                .route("count", count.clone());
+
+            NodeServer::new(0, router)
+                .layer(DeduplicateLayer::new(16))
+                .failure_policy(FailurePolicy::LogAndContinue)
+        }).await.unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_and_redeliveries_are_handled_once() {
+        let simulator = start(count_slowly).await;
+        simulator.set_link_faults("n0", "n1", LinkFaults::default().duplicate_probability(1.0));
+
+        let reply: Value = simulator.request("n0", json!({ "type": "relay" })).await.unwrap();
+        assert_eq!(reply["count"], 1);
+
+        simulator.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_request_whose_handler_panicked_is_handled_again() {
+        let simulator = start(count_after_a_panic).await;
+
+        let reply: Value = simulator.request("n0", json!({ "type": "relay" })).await.unwrap();
+        assert_eq!(reply["count"], 2);
+
+        simulator.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_of_a_definite_error_get_the_same_error() {
+        let simulator = start(count_and_fail).await;
+
+        // The first error never makes it back, so n0 has to retry
+        simulator.set_link_faults("n1", "n0", LinkFaults::default().drop_probability(1.0));
+
+        let heal = async {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            simulator.clear_link_faults();
+        };
+
+        let (result, _) = tokio::join!(simulator.request::<_, Value>("n0", json!({ "type": "relay" })), heal);
+        let err = result.unwrap_err();
+        let error = err.downcast_ref::<MaelstromError>().unwrap();
+
+        assert_eq!(error.code, ErrorCode::PreconditionFailed);
+        assert_eq!(error.text, "Asked 1 time(s)");
+
+        simulator.shutdown().await.unwrap();
+    }
+
+    #[test]
+    fn a_capacity_of_zero_still_evicts() {
+        let deduplicate = DeduplicateLayer::new(0).layer(());
+        let mut seen = deduplicate.seen.lock().unwrap();
+
+        for message_id in 0..3 {
+            seen.insert(("c1".to_string(), message_id));
+        }
+
+        assert_eq!(seen.replies.len(), 1);
+        assert_eq!(seen.order.len(), 1);
+    }
+
+    #[test]
+    fn forgotten_requests_do_not_evict_their_retries_early() {
+        let mut seen = SeenRequests {
+            replies: HashMap::new(),
+            order: VecDeque::new(),
+            capacity: 2,
+        };
+        let key = ("c1".to_string(), 1);
+
+        seen.insert(key.clone());
+        seen.forget(&key);
+        seen.insert(key.clone());
+        seen.insert(("c1".to_string(), 2));
+
+        assert!(seen.replies.contains_key(&key));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn token_bucket_refills_at_the_rate() {
+        let mut bucket = TokenBucket {
+            rate: 10.0,
+            burst: 2.0,
+            tokens: 2.0,
+            refilled_at: Instant::now(),
+        };
+
+        assert!(bucket.try_take());
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+    }
+}
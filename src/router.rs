@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+use futures::future::BoxFuture;
+use crate::{Message, MessageReply, Node};
+use crate::error::MaelstromError;
+use crate::handler::Handler;
+
+type RouteHandler<S> = Box<dyn Fn(Node<S>, Message<serde_json::Value>) -> BoxFuture<'static, MessageReply<serde_json::Value>> + Send + Sync>;
+
+/// Dispatches incoming messages to handlers by their `type`. Requests without a route are answered with a
+/// `not-supported` error, while unexpected replies (e.g. to an rpc that already timed out) are dropped.
+pub struct Router<S> {
+    routes: HashMap<String, RouteHandler<S>>,
+}
+
+impl<S> Router<S>
+where
+    S: Send + Sync + 'static,
+{
+    pub fn new() -> Self {
+        Self {
+            routes: HashMap::new(),
+        }
+    }
+
+    pub fn route<H, T>(mut self, message_type: &str, handler: H) -> Self
+    where
+        H: Handler<T, S>,
+    {
+        let handler = move |node, message| handler.call(node, message);
+
+        self.routes.insert(message_type.to_string(), Box::new(handler));
+        self
+    }
+
+    pub(crate) async fn handle(&self, node: Node<S>, message: Message<serde_json::Value>) -> MessageReply<serde_json::Value> {
+        let message_type = message.body.payload.get("type")
+            .and_then(|message_type| message_type.as_str())
+            .unwrap_or_default()
+            .to_string();
+
+        match self.routes.get(&message_type) {
+            Some(handler) => handler(node, message).await,
+            None if message.body.in_reply_to.is_some() => Ok(None),
+            None => Err(MaelstromError::not_supported(format!("Unsupported message type {message_type:?}")).into()),
+        }
+    }
+}
+
+impl<S> Default for Router<S>
+where
+    S: Send + Sync + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::{json, Value};
+    use crate::{NodeServer, Reply, RpcOptions};
+    use crate::error::{error_code, ErrorCode};
+    use crate::simulator::{NetworkConfig, Simulator};
+    use super::*;
+
+    /// Gives up on n1 long before it answers, so its reply turns up with nobody waiting for it
+    async fn relay(node: Node<()>) -> Reply<Value> {
+        let options = RpcOptions::default().timeout(Duration::from_millis(10));
+        let result = node.rpc_with_options::<_, Value>("n1".to_string(), json!({ "type": "slow" }), &options).await;
+
+        Reply(json!({ "type": "relay_ok", "timed_out": result.is_err() }))
+    }
+
+    async fn slow() -> Reply<Value> {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        Reply(json!({ "type": "slow_ok" }))
+    }
+
+    async fn start() -> Simulator {
+        Simulator::start(2, NetworkConfig::default(), || {
+            NodeServer::new((), Router::new().route("relay", relay).route("slow", slow))
+        }).await.unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unrouted_requests_are_not_supported() {
+        let simulator = start().await;
+
+        let err = simulator.request::<_, Value>("n0", json!({ "type": "frobnicate" })).await.unwrap_err();
+        assert_eq!(error_code(&err), Some(ErrorCode::NotSupported));
+
+        simulator.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replies_nobody_is_waiting_for_are_dropped() {
+        let simulator = start().await;
+
+        let reply: Value = simulator.request("n0", json!({ "type": "relay" })).await.unwrap();
+        assert_eq!(reply["timed_out"], true);
+
+        // Let the late `slow_ok` reach n0
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let stats = simulator.stats();
+        assert_eq!(stats.messages["slow_ok"].server, 1);
+        assert!(!stats.messages.contains_key("error"), "{:?}", stats.messages);
+
+        simulator.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,474 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::oneshot::Receiver;
+use tracing::{debug, debug_span, Instrument};
+use crate::Message;
+use crate::error::MaelstromError;
+use crate::stats::Stats;
+
+pub type OneshotSender<T> = tokio::sync::oneshot::Sender<T>;
+
+#[derive(Clone, Debug)]
+pub struct RpcOptions {
+    pub timeout: Option<Duration>,
+    /// `None` until a policy is set, which means a single attempt unless the call is idempotent (see
+    /// `ServiceClient::call_idempotent`). Setting `RetryPolicy::none()` rules out retries for those too.
+    pub retry: Option<RetryPolicy>,
+}
+
+impl RpcOptions {
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn no_timeout(mut self) -> Self {
+        self.timeout = None;
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+}
+
+/// A one second timeout and no retries, since repeating a `write`, `cas` or `send` whose reply was lost could apply
+/// it twice. Requests that are safe to repeat opt in, e.g. with `RetryPolicy::idempotent`.
+impl Default for RpcOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Some(Duration::from_secs(1)),
+            retry: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub backoff: Backoff,
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            backoff: Backoff::Fixed(Duration::ZERO),
+        }
+    }
+
+    /// For requests that can safely be repeated, like reads
+    pub fn idempotent() -> Self {
+        Self::exponential(Duration::from_millis(100), Duration::from_secs(1), 3)
+    }
+
+    pub fn fixed(delay: Duration, max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            backoff: Backoff::Fixed(delay),
+        }
+    }
+
+    pub fn exponential(initial: Duration, max: Duration, max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            backoff: Backoff::Exponential { initial, max, jitter: true },
+        }
+    }
+
+    /// Delay before retrying after the given (1-based) failed attempt, with any jitter drawn from `rng`
+    pub fn delay(&self, attempt: u32, rng: &mut impl Rng) -> Duration {
+        match self.backoff {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential { initial, max, jitter } => {
+                let delay = initial
+                    .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+                    .min(max);
+
+                if jitter && !delay.is_zero() {
+                    // Equal jitter: keep half of the delay and randomise the other half
+                    let half = delay / 2;
+                    half + rng.gen_range(Duration::ZERO..=half)
+                } else {
+                    delay
+                }
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Backoff {
+    Fixed(Duration),
+    Exponential {
+        initial: Duration,
+        max: Duration,
+        jitter: bool,
+    },
+}
+
+/// Parses a raw reply, turning Maelstrom `error` replies into a `MaelstromError`
+pub(crate) fn decode_reply<P: DeserializeOwned>(reply: serde_json::Value) -> Result<Message<P>> {
+    if reply.get("body").and_then(|body| body.get("type")).and_then(|ty| ty.as_str()) == Some("error") {
+        let error = serde_json::from_value::<Message<MaelstromError>>(reply)?;
+        return Err(error.body.payload.into());
+    }
+
+    Ok(serde_json::from_value(reply)?)
+}
+
+/// Every message a node sends goes through its outbox, which stamps it with a node-wide unique `msg_id`
+/// and keeps track of the requests that are still waiting for a reply
+pub(crate) struct Outbox {
+    // Counted in a u64 so that it can't wrap around to ids already in use
+    message_id: AtomicU64,
+    message_channel_tx: Sender<String>,
+    pending_replies: PendingReplies<serde_json::Value>,
+    sent: AtomicU64,
+    blocked_sends: AtomicU64,
+    message_stats: Arc<Stats>,
+    // Retry jitter, seeded so that simulated runs replay exactly
+    rng: Mutex<StdRng>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OutboundStats {
+    /// Messages waiting to be written to stdout
+    pub queue_depth: usize,
+    pub capacity: usize,
+    pub sent: u64,
+    /// Sends that found the queue full and had to wait for the writer
+    pub blocked_sends: u64,
+}
+
+impl Outbox {
+    pub fn new(tx: Sender<String>, message_stats: Arc<Stats>, seed: u64) -> Self {
+        Self {
+            message_id: AtomicU64::new(0),
+            message_channel_tx: tx,
+            pending_replies: PendingReplies::new(),
+            sent: AtomicU64::new(0),
+            blocked_sends: AtomicU64::new(0),
+            message_stats,
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+        }
+    }
+
+    pub fn stats(&self) -> OutboundStats {
+        let capacity = self.message_channel_tx.max_capacity();
+
+        OutboundStats {
+            queue_depth: capacity - self.message_channel_tx.capacity(),
+            capacity,
+            sent: self.sent.load(Ordering::Relaxed),
+            blocked_sends: self.blocked_sends.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Fails once every non-negative `i32` has been handed out, since Maelstrom message ids don't go any higher
+    pub fn next_message_id(&self) -> Result<i32> {
+        let message_id = self.message_id.fetch_add(1, Ordering::Relaxed);
+        i32::try_from(message_id).map_err(|_| eyre!("Ran out of message ids after sending {message_id} messages"))
+    }
+
+    /// Sends `message`, assigning it a fresh `msg_id` unless it already has one
+    pub async fn send<P: Serialize>(&self, mut message: Message<P>) -> Result<()> {
+        if message.body.message_id.is_none() {
+            message.body.message_id = Some(self.next_message_id()?);
+        }
+
+        let value = serde_json::to_value(&message)?;
+        let span = debug_span!(
+            "send",
+            dest = message.dest,
+            r#type = value["body"]["type"].as_str(),
+            msg_id = message.body.message_id,
+            in_reply_to = message.body.in_reply_to,
+        );
+
+        async {
+            let sent = match self.message_channel_tx.try_send(value.to_string()) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(line)) => {
+                    self.blocked_sends.fetch_add(1, Ordering::Relaxed);
+                    debug!("Outbound queue full, waiting for the writer");
+
+                    self.message_channel_tx.send(line).await.map_err(|_| ())
+                },
+                Err(TrySendError::Closed(_)) => Err(()),
+            };
+
+            sent.map_err(|_| eyre!("Failed to send message via message_channel"))?;
+            self.sent.fetch_add(1, Ordering::Relaxed);
+            self.message_stats.record_message(&value);
+
+            debug!("Queued message");
+            Ok(())
+        }.instrument(span).await
+    }
+
+    /// Sends `message` and waits for its reply, retrying according to `options`. Every attempt carries the same
+    /// message id, so a late reply to an earlier attempt still completes the call, and the receiver can tell a retry
+    /// from a new request (see `middleware::DeduplicateLayer`).
+    pub async fn call<P: Serialize + Clone>(&self, mut message: Message<P>, options: &RpcOptions) -> Result<serde_json::Value> {
+        let retry = options.retry.clone().unwrap_or_else(RetryPolicy::none);
+        let max_attempts = retry.max_attempts.max(1);
+        let message_id = self.next_message_id()?;
+        message.body.message_id = Some(message_id);
+
+        let mut pending = self.pending_replies.register(message_id);
+
+        for attempt in 1..=max_attempts {
+            self.send(message.clone()).await?;
+
+            if let Some(reply) = pending.recv(options.timeout).await? {
+                return Ok(reply);
+            }
+
+            debug!(message_id, attempt, "No reply within {:?}", options.timeout);
+
+            if attempt < max_attempts {
+                let delay = retry.delay(attempt, &mut *self.rng.lock().unwrap());
+
+                // The reply may still turn up while backing off
+                if let Some(reply) = pending.recv(Some(delay)).await? {
+                    return Ok(reply);
+                }
+            }
+        }
+
+        Err(MaelstromError::timeout(format!("Request timed out after {max_attempts} attempt(s)")).into())
+    }
+
+    /// Hands a reply to the call waiting on `in_reply_to`, giving the reply back if nothing is waiting
+    pub fn resolve(&self, in_reply_to: i32, reply: serde_json::Value) -> Result<(), serde_json::Value> {
+        self.pending_replies.resolve(in_reply_to, reply)
+    }
+}
+
+pub(crate) struct PendingReplies<T> {
+    reply_senders: Mutex<HashMap<i32, OneshotSender<T>>>,
+}
+
+impl<T> PendingReplies<T> {
+    pub fn new() -> Self {
+        Self {
+            reply_senders: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn resolve(&self, in_reply_to: i32, reply: T) -> Result<(), T> {
+        let tx = self.reply_senders.lock().unwrap().remove(&in_reply_to);
+
+        match tx {
+            // The receiver may have been dropped since, in which case the reply is discarded
+            Some(tx) => {
+                let _ = tx.send(reply);
+                Ok(())
+            },
+            None => Err(reply),
+        }
+    }
+
+    pub fn register(&self, message_id: i32) -> PendingReply<'_, T> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.reply_senders.lock().unwrap().insert(message_id, tx);
+
+        PendingReply {
+            message_id,
+            rx,
+            pending: self,
+        }
+    }
+}
+
+/// Removes its reply sender when dropped, so timed out or cancelled calls don't leak entries
+pub(crate) struct PendingReply<'a, T> {
+    message_id: i32,
+    rx: Receiver<T>,
+    pending: &'a PendingReplies<T>,
+}
+
+impl<T> PendingReply<'_, T> {
+    /// Waits up to `timeout` for the reply, giving `None` if it doesn't arrive in time
+    pub async fn recv(&mut self, timeout: Option<Duration>) -> Result<Option<T>> {
+        let reply = match timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, &mut self.rx).await {
+                Ok(reply) => reply,
+                Err(_) => return Ok(None),
+            },
+            None => (&mut self.rx).await,
+        };
+
+        Ok(Some(reply.map_err(|_| eyre!("Reply sender dropped"))?))
+    }
+}
+
+impl<T> Drop for PendingReply<'_, T> {
+    fn drop(&mut self) {
+        self.pending.reply_senders.lock().unwrap().remove(&self.message_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use tokio::sync::mpsc::Receiver;
+    use tokio::time::Instant;
+    use crate::error::{error_code, ErrorCode};
+    use crate::MessageBody;
+    use super::*;
+
+    fn outbox() -> (Arc<Outbox>, Receiver<String>) {
+        let (tx, rx) = tokio::sync::mpsc::channel(64);
+        let outbox = Outbox::new(tx, Arc::new(Stats::new(["n0".to_string(), "n1".to_string()])), 1);
+
+        (Arc::new(outbox), rx)
+    }
+
+    fn request() -> Message<Value> {
+        Message {
+            src: "n0".to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody {
+                message_id: None,
+                in_reply_to: None,
+                payload: json!({ "type": "read" }),
+            },
+        }
+    }
+
+    fn no_jitter(initial: Duration, max: Duration) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 10,
+            backoff: Backoff::Exponential { initial, max, jitter: false },
+        }
+    }
+
+    fn is_empty(outbox: &Outbox) -> bool {
+        outbox.pending_replies.reply_senders.lock().unwrap().is_empty()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn times_out_after_the_timeout() {
+        let (outbox, mut sent) = outbox();
+        let options = RpcOptions::default().timeout(Duration::from_millis(300));
+
+        let start = Instant::now();
+        let err = outbox.call(request(), &options).await.unwrap_err();
+
+        assert_eq!(error_code(&err), Some(ErrorCode::Timeout));
+        assert_eq!(start.elapsed(), Duration::from_millis(300));
+        assert!(sent.try_recv().is_ok());
+        assert!(sent.try_recv().is_err(), "retried without a retry policy");
+        assert!(is_empty(&outbox));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_carry_the_same_message_id() {
+        let (outbox, mut sent) = outbox();
+        let options = RpcOptions::default()
+            .timeout(Duration::from_millis(100))
+            .retry(RetryPolicy { max_attempts: 3, ..no_jitter(Duration::from_millis(50), Duration::from_secs(1)) });
+
+        let start = Instant::now();
+        let err = outbox.call(request(), &options).await.unwrap_err();
+        assert_eq!(error_code(&err), Some(ErrorCode::Timeout));
+
+        // Three timeouts, with 50ms and then 100ms of backoff in between
+        assert_eq!(start.elapsed(), Duration::from_millis(450));
+
+        let message_ids = std::iter::from_fn(|| sent.try_recv().ok())
+            .map(|line| serde_json::from_str::<Value>(&line).unwrap()["body"]["msg_id"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(message_ids, [json!(0), json!(0), json!(0)]);
+        assert!(is_empty(&outbox));
+    }
+
+    #[tokio::test]
+    async fn message_ids_never_wrap_around() {
+        let (outbox, mut sent) = outbox();
+        outbox.message_id.store(i32::MAX as u64, Ordering::Relaxed);
+
+        outbox.send(request()).await.unwrap();
+        let line = sent.try_recv().unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&line).unwrap()["body"]["msg_id"], i32::MAX);
+
+        assert!(outbox.send(request()).await.is_err());
+        assert!(outbox.next_message_id().is_err());
+        assert!(sent.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_reply_during_backoff_completes_the_call() {
+        let (outbox, _sent) = outbox();
+        let options = RpcOptions::default()
+            .timeout(Duration::from_millis(100))
+            .retry(RetryPolicy::fixed(Duration::from_millis(500), 2));
+
+        let call = tokio::spawn({
+            let outbox = outbox.clone();
+            async move { outbox.call(request(), &options).await }
+        });
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        outbox.resolve(0, json!({ "type": "read_ok" })).unwrap();
+
+        assert_eq!(call.await.unwrap().unwrap(), json!({ "type": "read_ok" }));
+        assert!(is_empty(&outbox));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_dropped_call_forgets_its_reply() {
+        let (outbox, _sent) = outbox();
+        let options = RpcOptions::default().no_timeout();
+
+        let result = tokio::time::timeout(Duration::from_millis(100), outbox.call(request(), &options)).await;
+        assert!(result.is_err());
+        assert!(is_empty(&outbox));
+
+        // A late reply is handed back instead of going to the abandoned call
+        assert!(outbox.resolve(0, json!({ "type": "read_ok" })).is_err());
+    }
+
+    #[test]
+    fn exponential_backoff_doubles_up_to_the_max() {
+        let policy = no_jitter(Duration::from_millis(100), Duration::from_millis(1000));
+        let mut rng = StdRng::seed_from_u64(1);
+
+        let delays = (1..=6)
+            .map(|attempt| policy.delay(attempt, &mut rng).as_millis())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+
+        // Huge attempt counts saturate rather than overflow
+        assert_eq!(policy.delay(u32::MAX, &mut rng), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn jitter_keeps_at_least_half_of_the_delay() {
+        let policy = RetryPolicy::exponential(Duration::from_millis(100), Duration::from_millis(1000), 10);
+        let mut rng = StdRng::seed_from_u64(2);
+
+        for attempt in 1..=6 {
+            let delay = no_jitter(Duration::from_millis(100), Duration::from_millis(1000)).delay(attempt, &mut rng);
+
+            for _ in 0..100 {
+                let jittered = policy.delay(attempt, &mut rng);
+                assert!(jittered >= delay / 2 && jittered <= delay, "{jittered:?} outside of [{:?}, {delay:?}]", delay / 2);
+            }
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::sync::Arc;
+
+use color_eyre::eyre::eyre;
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use tracing::{debug_span, Instrument};
+use crate::{Message, MessageBody, NodeId};
+use crate::rpc::{decode_reply, Outbox, RetryPolicy, RpcOptions};
+
+pub const TIMESTAMP_ORACLE_ID: &str = "lin-tso";
+
+/// Client for one of Maelstrom's built-in services (or any other node that only answers requests).
+/// Late replies from `service_id` are dropped by `Node::serve` rather than passed to the message handler.
+pub struct ServiceClient {
+    service_id: NodeId,
+    node_id: NodeId,
+    outbox: Arc<Outbox>,
+    rpc_options: RpcOptions,
+}
+
+impl ServiceClient {
+    pub(crate) fn new(service_id: NodeId, node_id: NodeId, outbox: Arc<Outbox>, rpc_options: RpcOptions) -> Self {
+        Self {
+            service_id,
+            node_id,
+            outbox,
+            rpc_options,
+        }
+    }
+
+    pub fn service_id(&self) -> &str {
+        &self.service_id
+    }
+
+    pub async fn call<Req, Resp>(&self, payload: Req) -> Result<Resp>
+    where
+        Req: Serialize + Clone,
+        Resp: DeserializeOwned,
+    {
+        self.call_with_options(payload, &self.rpc_options).await
+    }
+
+    /// Like `call`, but retries with `RetryPolicy::idempotent` unless the configured options set a policy of their
+    /// own, `RetryPolicy::none()` included. Only for requests that can safely be repeated.
+    pub async fn call_idempotent<Req, Resp>(&self, payload: Req) -> Result<Resp>
+    where
+        Req: Serialize + Clone,
+        Resp: DeserializeOwned,
+    {
+        match self.rpc_options.retry {
+            Some(_) => self.call_with_options(payload, &self.rpc_options).await,
+            None => self.call_with_options(payload, &self.rpc_options.clone().retry(RetryPolicy::idempotent())).await,
+        }
+    }
+
+    pub async fn call_with_options<Req, Resp>(&self, payload: Req, options: &RpcOptions) -> Result<Resp>
+    where
+        Req: Serialize + Clone,
+        Resp: DeserializeOwned,
+    {
+        let message = Message {
+            src: self.node_id.clone(),
+            dest: self.service_id.clone(),
+            body: MessageBody {
+                message_id: None,
+                in_reply_to: None,
+                payload,
+            },
+        };
+
+        let reply = self.outbox.call(message, options)
+            .instrument(debug_span!("service_call", service = self.service_id))
+            .await
+            .map_err(|err| err.wrap_err(format!("{} request failed", self.service_id)))?;
+
+        Ok(decode_reply::<Resp>(reply)?.body.payload)
+    }
+}
+
+pub struct TimestampOracle {
+    client: Arc<ServiceClient>,
+}
+
+impl TimestampOracle {
+    pub fn new(client: Arc<ServiceClient>) -> Self {
+        Self {
+            client,
+        }
+    }
+
+    pub async fn ts(&self) -> Result<u64> {
+        match self.client.call(TimestampOraclePayload::Ts).await? {
+            TimestampOraclePayload::TsOk { ts } => Ok(ts),
+            _ => Err(eyre!("Wrong {TIMESTAMP_ORACLE_ID} reply type")),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum TimestampOraclePayload {
+    Ts,
+    TsOk {
+        ts: u64,
+    },
+}
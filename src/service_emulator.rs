@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+
+use color_eyre::Result;
+use crate::{CasOutcome, Consistency, KeyValueStore, LINEARIZABLE_KV_STORE_ID, LWW_KV_STORE_ID, MaelstromError, MemoryKVStore, Message, NodeId, SEQUENTIAL_KV_STORE_ID, TIMESTAMP_ORACLE_ID};
+use crate::kv_store::KVStorePayload;
+use crate::service::TimestampOraclePayload;
+
+/// In-process stand-ins for Maelstrom's `seq-kv`, `lin-kv`, `lww-kv` and `lin-tso` services, speaking the same
+/// messages as `KVStore` and `TimestampOracle`. Every client gets a session of its own, so on `seq-kv` it only
+/// ever sees its own writes and what it read before, while other clients may read older values.
+pub struct ServiceEmulator {
+    stores: HashMap<&'static str, MemoryKVStore>,
+    sessions: Mutex<HashMap<(NodeId, NodeId), Arc<MemoryKVStore>>>,
+    timestamp: AtomicU64,
+    message_id: AtomicI32,
+}
+
+impl ServiceEmulator {
+    pub fn new() -> Self {
+        Self::with_stores(MemoryKVStore::new)
+    }
+
+    /// Makes the staleness of `seq-kv` reads and the clock skew of `lww-kv` clients reproducible
+    pub fn seeded(seed: u64) -> Self {
+        Self::with_stores(|consistency| MemoryKVStore::seeded(consistency, seed))
+    }
+
+    fn with_stores(store: impl Fn(Consistency) -> MemoryKVStore) -> Self {
+        Self {
+            stores: HashMap::from([
+                (SEQUENTIAL_KV_STORE_ID, store(Consistency::Sequential)),
+                (LINEARIZABLE_KV_STORE_ID, store(Consistency::Linearizable)),
+                (LWW_KV_STORE_ID, store(Consistency::LastWriteWins)),
+            ]),
+            sessions: Mutex::new(HashMap::new()),
+            timestamp: AtomicU64::new(0),
+            message_id: AtomicI32::new(0),
+        }
+    }
+
+    pub fn handles(&self, service_id: &str) -> bool {
+        service_id == TIMESTAMP_ORACLE_ID || self.stores.contains_key(service_id)
+    }
+
+    /// Answers a request to one of the services. Returns `None` for messages that aren't addressed to a service or
+    /// don't expect a reply.
+    pub async fn handle(&self, request: Message<serde_json::Value>) -> Option<Message<serde_json::Value>> {
+        if !self.handles(&request.dest) || request.body.message_id.is_none() {
+            return None;
+        }
+
+        let (request, payload) = request.take_payload();
+
+        let reply = if request.dest == TIMESTAMP_ORACLE_ID {
+            self.timestamp_request(payload)
+        } else {
+            self.kv_request(&request.dest, &request.src, payload).await
+        };
+
+        let reply = reply.or_else(|err| {
+            let error = err.downcast::<MaelstromError>()
+                .unwrap_or_else(|err| MaelstromError::crash(format!("{err:#}")));
+
+            serde_json::to_value(error)
+        }).ok()?;
+
+        let mut reply = request.into_reply(reply);
+        reply.body.message_id = Some(self.message_id.fetch_add(1, Ordering::Relaxed));
+
+        Some(reply)
+    }
+
+    fn timestamp_request(&self, payload: serde_json::Value) -> Result<serde_json::Value> {
+        match serde_json::from_value(payload).map_err(|err| MaelstromError::malformed_request(err.to_string()))? {
+            TimestampOraclePayload::Ts => {
+                let ts = self.timestamp.fetch_add(1, Ordering::Relaxed) + 1;
+                Ok(serde_json::to_value(TimestampOraclePayload::TsOk { ts })?)
+            },
+            _ => Err(MaelstromError::not_supported("Only ts requests are supported").into()),
+        }
+    }
+
+    async fn kv_request(&self, service_id: &str, client: &str, payload: serde_json::Value) -> Result<serde_json::Value> {
+        let store = self.sessions.lock().unwrap()
+            .entry((service_id.to_string(), client.to_string()))
+            .or_insert_with(|| Arc::new(self.stores[service_id].clone()))
+            .clone();
+
+        let reply = match serde_json::from_value(payload).map_err(|err| MaelstromError::malformed_request(err.to_string()))? {
+            KVStorePayload::Read { key } => KVStorePayload::ReadOk {
+                value: store.read(key).await?,
+            },
+            KVStorePayload::Write { key, value } => {
+                store.write(key, value).await?;
+                KVStorePayload::WriteOk
+            },
+            KVStorePayload::Cas { key, from, to, create_if_not_exists } => {
+                match store.cas_with(key.clone(), from.clone(), to, create_if_not_exists).await? {
+                    CasOutcome::Ok => KVStorePayload::CasOk,
+                    CasOutcome::PreconditionFailed { current } => {
+                        return Err(MaelstromError::precondition_failed(format!("Expected {from}, but had {current}")).into());
+                    },
+                    CasOutcome::KeyMissing => {
+                        return Err(MaelstromError::key_does_not_exist(format!("Key {key} does not exist")).into());
+                    },
+                }
+            },
+            _ => return Err(MaelstromError::not_supported("Only read, write and cas requests are supported").into()),
+        };
+
+        Ok(serde_json::to_value(reply)?)
+    }
+}
+
+impl Default for ServiceEmulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use super::*;
+
+    async fn request(services: &ServiceEmulator, client: &str, dest: &str, body: Value) -> Option<Value> {
+        let request = serde_json::from_value(json!({ "src": client, "dest": dest, "body": body })).unwrap();
+        let reply = services.handle(request).await?;
+
+        assert_eq!(reply.src, dest);
+        assert_eq!(reply.dest, client);
+        Some(serde_json::to_value(reply.body).unwrap())
+    }
+
+    async fn read(services: &ServiceEmulator, client: &str) -> i32 {
+        let reply = request(services, client, SEQUENTIAL_KV_STORE_ID, json!({ "type": "read", "key": "a", "msg_id": 1 })).await.unwrap();
+        reply["value"].as_str().unwrap().parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn seq_kv_clients_may_read_stale_values_but_never_go_back() {
+        let services = ServiceEmulator::seeded(1);
+
+        for value in 1..=10 {
+            let reply = request(&services, "n0", SEQUENTIAL_KV_STORE_ID, json!({ "type": "write", "key": "a", "value": value.to_string(), "msg_id": value })).await.unwrap();
+            assert_eq!(reply["type"], "write_ok");
+        }
+
+        // The writer always sees its own last write, while other clients start out anywhere in the past
+        assert_eq!(read(&services, "n0").await, 10);
+
+        let mut first_reads = Vec::new();
+        for client in 1..=20 {
+            first_reads.push(read(&services, &format!("n{client}")).await);
+        }
+        assert!(first_reads.iter().any(|value| *value < 10), "{first_reads:?}");
+
+        let mut last = 0;
+        for _ in 0..20 {
+            let value = read(&services, "n1").await;
+            assert!(value >= last, "n1 read {value} after {last}");
+            last = value;
+        }
+    }
+
+    #[tokio::test]
+    async fn cas_reports_failed_preconditions_and_missing_keys() {
+        let services = ServiceEmulator::seeded(2);
+
+        let reply = request(&services, "n0", LINEARIZABLE_KV_STORE_ID, json!({ "type": "cas", "key": "a", "from": "1", "to": "2", "create_if_not_exists": false, "msg_id": 1 })).await.unwrap();
+        assert_eq!(reply["type"], "error");
+        assert_eq!(reply["code"], 20);
+        assert_eq!(reply["in_reply_to"], 1);
+
+        let reply = request(&services, "n0", LINEARIZABLE_KV_STORE_ID, json!({ "type": "cas", "key": "a", "from": "1", "to": "2", "create_if_not_exists": true, "msg_id": 2 })).await.unwrap();
+        assert_eq!(reply["type"], "cas_ok");
+
+        let reply = request(&services, "n1", LINEARIZABLE_KV_STORE_ID, json!({ "type": "cas", "key": "a", "from": "1", "to": "3", "create_if_not_exists": false, "msg_id": 3 })).await.unwrap();
+        assert_eq!(reply["type"], "error");
+        assert_eq!(reply["code"], 22);
+
+        let reply = request(&services, "n1", LINEARIZABLE_KV_STORE_ID, json!({ "type": "cas", "key": "a", "from": "2", "to": "3", "create_if_not_exists": false, "msg_id": 4 })).await.unwrap();
+        assert_eq!(reply["type"], "cas_ok");
+    }
+
+    #[tokio::test]
+    async fn timestamps_always_increase() {
+        let services = ServiceEmulator::new();
+        let mut last = 0;
+
+        for message_id in 0..50 {
+            let client = format!("n{}", message_id % 3);
+            let reply = request(&services, &client, TIMESTAMP_ORACLE_ID, json!({ "type": "ts", "msg_id": message_id })).await.unwrap();
+
+            assert_eq!(reply["type"], "ts_ok");
+            let ts = reply["ts"].as_u64().unwrap();
+            assert!(ts > last, "ts {ts} after {last}");
+            last = ts;
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_without_a_msg_id_get_no_reply() {
+        let services = ServiceEmulator::new();
+
+        assert!(request(&services, "n0", SEQUENTIAL_KV_STORE_ID, json!({ "type": "write", "key": "a", "value": "1" })).await.is_none());
+        assert!(request(&services, "n0", TIMESTAMP_ORACLE_ID, json!({ "type": "ts" })).await.is_none());
+
+        // Nothing was written either
+        let reply = request(&services, "n0", SEQUENTIAL_KV_STORE_ID, json!({ "type": "read", "key": "a", "msg_id": 1 })).await.unwrap();
+        assert_eq!(reply["code"], 20);
+    }
+}
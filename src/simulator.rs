@@ -0,0 +1,566 @@
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::Duration;
+
+use color_eyre::{Report, Result};
+use futures::StreamExt;
+use rand::{Rng, SeedableRng};
+use rand::seq::SliceRandom;
+use rand::rngs::StdRng;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+use crate::{Init, InitOk, MaelstromError, Message, MessageBody, NodeId, NodeServer, ServiceEmulator, Stats, StatsSummary};
+use crate::history::History;
+use crate::rpc::{decode_reply, PendingReplies};
+use crate::transport::{Input, Output, Transport};
+
+/// The client requests are sent from unless another one is given to `Simulator::request_from`
+pub const DEFAULT_CLIENT_ID: &str = "c1";
+
+#[derive(Clone, Debug)]
+pub struct NetworkConfig {
+    seed: u64,
+    faults: LinkFaults,
+    request_timeout: Duration,
+}
+
+impl NetworkConfig {
+    /// Seeds every random choice the network and the nodes' retries make, so a run can be replayed by reusing its seed
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Faults for every link between two nodes that has none of its own. Messages from and to clients only
+    /// get the latency.
+    pub fn faults(mut self, faults: LinkFaults) -> Self {
+        self.faults = faults;
+        self
+    }
+
+    /// How long `Simulator::request` waits for a reply before failing with a `timeout` error
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            faults: LinkFaults::default(),
+            request_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Latency {
+    Constant(Duration),
+    Uniform(Range<Duration>),
+    Exponential {
+        mean: Duration,
+    },
+}
+
+impl Latency {
+    fn sample(&self, rng: &mut StdRng) -> Duration {
+        match self {
+            Latency::Constant(latency) => *latency,
+            Latency::Uniform(range) if range.is_empty() => range.start,
+            Latency::Uniform(range) => rng.gen_range(range.clone()),
+            Latency::Exponential { mean } => mean.mul_f64(-(1.0 - rng.gen::<f64>()).ln()),
+        }
+    }
+}
+
+impl From<Range<Duration>> for Latency {
+    fn from(range: Range<Duration>) -> Self {
+        Latency::Uniform(range)
+    }
+}
+
+impl From<Duration> for Latency {
+    fn from(latency: Duration) -> Self {
+        Latency::Constant(latency)
+    }
+}
+
+/// How a link between two nodes misbehaves. Links deliver in order unless `reorder_probability` is set.
+#[derive(Clone, Debug)]
+pub struct LinkFaults {
+    latency: Latency,
+    drop_probability: f64,
+    duplicate_probability: f64,
+    reorder_probability: f64,
+}
+
+impl LinkFaults {
+    pub fn latency(mut self, latency: impl Into<Latency>) -> Self {
+        self.latency = latency.into();
+        self
+    }
+
+    pub fn drop_probability(mut self, probability: f64) -> Self {
+        self.drop_probability = valid_probability(probability);
+        self
+    }
+
+    /// Chance of a message being delivered twice, each copy with its own latency
+    pub fn duplicate_probability(mut self, probability: f64) -> Self {
+        self.duplicate_probability = valid_probability(probability);
+        self
+    }
+
+    /// Chance of a message being held back by an extra latency and overtaken by the ones sent after it
+    pub fn reorder_probability(mut self, probability: f64) -> Self {
+        self.reorder_probability = valid_probability(probability);
+        self
+    }
+}
+
+/// Probabilities outside of `[0, 1]` are clamped, and NaN or infinite ones count as zero
+fn valid_probability(probability: f64) -> f64 {
+    match probability.is_finite() {
+        true => probability.clamp(0.0, 1.0),
+        false => 0.0,
+    }
+}
+
+impl Default for LinkFaults {
+    fn default() -> Self {
+        Self {
+            latency: Latency::Uniform(Duration::from_millis(1)..Duration::from_millis(10)),
+            drop_probability: 0.0,
+            duplicate_probability: 0.0,
+            reorder_probability: 0.0,
+        }
+    }
+}
+
+/// The partitions of Maelstrom's partition nemesis. Nodes are assigned to sides at random.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Partition {
+    /// Two halves that can't reach each other
+    Halves,
+    /// Nodes arranged in a ring, each only hearing from the majority of nodes closest to it, so no two nodes
+    /// agree on what the majority is
+    MajoritiesRing,
+    /// A single node cut off from every other
+    IsolateOne,
+}
+
+/// Runs a cluster of nodes in one process, connected by a simulated network instead of Maelstrom.
+///
+/// Message latencies and faults are drawn from a seeded rng, and every node gets the same seed for its retry jitter
+/// (see `NodeServer::seed`). Run it on a current-thread runtime with the clock paused
+/// (e.g. `#[tokio::test(start_paused = true)]`) so that a seed always plays out the same way, and sleeps, task
+/// periods and timeouts take no real time. Replays are only exact if the nodes themselves are deterministic too:
+/// iterating a `HashMap` or drawing from `thread_rng` in a handler makes each run different.
+pub struct Simulator {
+    network: Arc<Network>,
+    node_ids: Vec<NodeId>,
+    nodes: JoinSet<Result<()>>,
+    seed: u64,
+    request_timeout: Duration,
+    history: History,
+    shutdown: CancellationToken,
+}
+
+struct Network {
+    inboxes: HashMap<NodeId, UnboundedSender<String>>,
+    faults: LinkFaults,
+    services: ServiceEmulator,
+    stats: Stats,
+    state: Mutex<NetworkState>,
+    client_message_id: AtomicI32,
+    pending_replies: PendingReplies<serde_json::Value>,
+}
+
+struct NetworkState {
+    rng: StdRng,
+    // Directed (src, dest) links that currently drop everything
+    partitioned: HashSet<(NodeId, NodeId)>,
+    link_faults: HashMap<(NodeId, NodeId), LinkFaults>,
+    // When the last in-order message on each link is due, so later ones aren't delivered before it
+    last_delivery: HashMap<(NodeId, NodeId), Instant>,
+}
+
+impl Simulator {
+    /// Starts `node_count` nodes named `n0`, `n1`, ..., each built by `server`, and initialises them
+    pub async fn start<S, F>(node_count: usize, config: NetworkConfig, server: F) -> Result<Self>
+    where
+        S: Send + Sync + 'static,
+        F: Fn() -> NodeServer<S>,
+    {
+        let node_ids = (0..node_count)
+            .map(|index| format!("n{index}"))
+            .collect::<Vec<_>>();
+
+        let (inboxes, receivers): (HashMap<_, _>, Vec<_>) = node_ids.iter()
+            .map(|node_id| {
+                let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+                ((node_id.clone(), tx), rx)
+            })
+            .unzip();
+
+        let network = Arc::new(Network {
+            inboxes,
+            faults: config.faults,
+            services: ServiceEmulator::seeded(config.seed),
+            stats: Stats::new(node_ids.iter().cloned()),
+            state: Mutex::new(NetworkState {
+                rng: StdRng::seed_from_u64(config.seed),
+                partitioned: HashSet::new(),
+                link_faults: HashMap::new(),
+                last_delivery: HashMap::new(),
+            }),
+            client_message_id: AtomicI32::new(0),
+            pending_replies: PendingReplies::new(),
+        });
+
+        let shutdown = CancellationToken::new();
+        let mut nodes = JoinSet::new();
+
+        for inbox in receivers {
+            let transport = SimulatedTransport {
+                network: network.clone(),
+                inbox,
+            };
+
+            let server = server().seed(config.seed);
+            let shutdown = shutdown.child_token();
+
+            nodes.spawn(async move {
+                let (input, output) = transport.connect().await?;
+                server.serve_on(input, output, shutdown).await
+            });
+        }
+
+        let simulator = Self {
+            network,
+            node_ids,
+            nodes,
+            seed: config.seed,
+            request_timeout: config.request_timeout,
+            history: History::new(),
+            shutdown,
+        };
+
+        for node_id in &simulator.node_ids {
+            let init = Init {
+                node_id: node_id.clone(),
+                node_ids: simulator.node_ids.clone(),
+            };
+
+            let _: InitOk = simulator.exchange("c0", node_id, init, false).await?;
+        }
+
+        Ok(simulator)
+    }
+
+    pub fn node_ids(&self) -> &[NodeId] {
+        &self.node_ids
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Cuts the cluster up as described by `partition`, replacing any partition already in place
+    pub fn partition(&self, partition: Partition) {
+        let mut state = self.network.state.lock().unwrap();
+
+        let mut ring = self.node_ids.clone();
+        ring.shuffle(&mut state.rng);
+
+        let mut partitioned = HashSet::new();
+        let mut cut = |src: &NodeId, dest: &NodeId| {
+            partitioned.insert((src.clone(), dest.clone()));
+        };
+
+        match partition {
+            Partition::Halves => {
+                let (left, right) = ring.split_at(ring.len() / 2);
+
+                for (a, b) in left.iter().flat_map(|a| right.iter().map(move |b| (a, b))) {
+                    cut(a, b);
+                    cut(b, a);
+                }
+            },
+            Partition::MajoritiesRing => {
+                let len = ring.len();
+                let majority = len / 2 + 1;
+
+                // Each node hears from the majority centred on it, going round the ring
+                for (position, dest) in ring.iter().enumerate() {
+                    let first = position + len - (majority - 1) / 2;
+                    let heard = (first..first + majority)
+                        .map(|index| &ring[index % len])
+                        .collect::<HashSet<_>>();
+
+                    for src in ring.iter().filter(|src| !heard.contains(src)) {
+                        cut(src, dest);
+                    }
+                }
+            },
+            Partition::IsolateOne => {
+                if let Some((isolated, others)) = ring.split_first() {
+                    for other in others {
+                        cut(isolated, other);
+                        cut(other, isolated);
+                    }
+                }
+            },
+        }
+
+        info!(?partition, links = partitioned.len(), "Partitioned network");
+        state.partitioned = partitioned;
+    }
+
+    pub fn heal(&self) {
+        self.network.state.lock().unwrap().partitioned.clear();
+        info!("Healed network");
+    }
+
+    /// Overrides the `NetworkConfig` faults for messages from `src` to `dest`
+    pub fn set_link_faults(&self, src: &str, dest: &str, faults: LinkFaults) {
+        self.network.state.lock().unwrap().link_faults.insert((src.to_string(), dest.to_string()), faults);
+    }
+
+    pub fn clear_link_faults(&self) {
+        self.network.state.lock().unwrap().link_faults.clear();
+    }
+
+    /// Sends `payload` to `node_id` from `DEFAULT_CLIENT_ID` and waits for the reply. An `error` reply is returned
+    /// as a `MaelstromError`.
+    pub async fn request<Req, Resp>(&self, node_id: &str, payload: Req) -> Result<Resp>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+    {
+        self.request_from(DEFAULT_CLIENT_ID, node_id, payload).await
+    }
+
+    pub async fn request_from<Req, Resp>(&self, client_id: &str, node_id: &str, payload: Req) -> Result<Resp>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+    {
+        self.exchange(client_id, node_id, payload, true).await
+    }
+
+    /// Every request sent through `request` and `request_from` so far, and the replies to them
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    /// Every message sent over the network, and how long requests sent through `request` and `request_from` took
+    /// to be answered
+    pub fn stats(&self) -> StatsSummary {
+        self.network.stats.summary()
+    }
+
+    async fn exchange<Req, Resp>(&self, client_id: &str, node_id: &str, payload: Req, record: bool) -> Result<Resp>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+    {
+        let message_id = self.network.client_message_id.fetch_add(1, Ordering::Relaxed);
+
+        let message = serde_json::to_value(Message {
+            src: client_id.to_string(),
+            dest: node_id.to_string(),
+            body: MessageBody {
+                message_id: Some(message_id),
+                in_reply_to: None,
+                payload,
+            },
+        })?;
+
+        if record {
+            self.history.record_request(&message);
+        }
+
+        let sent_at = Instant::now();
+        let mut pending = self.network.pending_replies.register(message_id);
+        self.network.route(message.to_string());
+
+        let reply = pending.recv(Some(self.request_timeout))
+            .await?
+            .ok_or_else(|| MaelstromError::timeout(format!("No reply from {node_id} within {:?}", self.request_timeout)))?;
+
+        if record {
+            self.history.record_reply(&reply);
+
+            if reply["body"]["type"] != "error" {
+                let request_type = message["body"]["type"].as_str().unwrap_or_default();
+                self.network.stats.record_latency(request_type, sent_at.elapsed());
+            }
+        }
+
+        Ok(decode_reply::<Resp>(reply)?.body.payload)
+    }
+
+    /// Shuts every node down as if its stdin was closed, returning the first error a node failed with
+    pub async fn shutdown(mut self) -> Result<()> {
+        self.shutdown.cancel();
+
+        while let Some(res) = self.nodes.join_next().await {
+            res??;
+        }
+
+        Ok(())
+    }
+}
+
+impl Network {
+    /// Delivers a message after a random delay, subject to the faults of its link. Requests to the key-value services
+    /// and `lin-tso` are answered by the emulator, and anything else not addressed to a node is taken to be a reply to
+    /// a client.
+    fn route(self: &Arc<Self>, line: String) {
+        let value = match serde_json::from_str::<serde_json::Value>(&line) {
+            Ok(value) => value,
+            Err(err) => {
+                warn!("Dropping malformed message {line:?}: {err}");
+                return;
+            },
+        };
+
+        self.stats.record_message(&value);
+
+        let src = value["src"].as_str().unwrap_or_default().to_string();
+        let dest = value["dest"].as_str().unwrap_or_default().to_string();
+
+        let deliveries = {
+            let mut state = self.state.lock().unwrap();
+            state.schedule(self, src, dest.clone())
+        };
+
+        let value = Arc::new(value);
+        let line = Arc::new(line);
+
+        for deliver_at in deliveries {
+            let network = self.clone();
+            let (value, line, dest) = (value.clone(), line.clone(), dest.clone());
+
+            tokio::spawn(async move {
+                tokio::time::sleep_until(deliver_at).await;
+                network.deliver(&dest, &value, &line).await;
+            });
+        }
+    }
+
+    async fn deliver(self: &Arc<Self>, dest: &str, value: &serde_json::Value, line: &str) {
+        if let Some(inbox) = self.inboxes.get(dest) {
+            let _ = inbox.send(line.to_string());
+            return;
+        }
+
+        if self.services.handles(dest) {
+            let reply = match serde_json::from_value::<Message<serde_json::Value>>(value.clone()) {
+                Ok(request) => self.services.handle(request).await,
+                Err(err) => {
+                    warn!("Dropping malformed request {line:?}: {err}");
+                    return;
+                },
+            };
+
+            if let Some(reply) = reply.and_then(|reply| serde_json::to_string(&reply).ok()) {
+                self.route(reply);
+            }
+
+            return;
+        }
+
+        let resolved = value["body"]["in_reply_to"].as_i64()
+            .and_then(|in_reply_to| i32::try_from(in_reply_to).ok())
+            .is_some_and(|in_reply_to| self.pending_replies.resolve(in_reply_to, value.clone()).is_ok());
+
+        if !resolved {
+            debug!("Dropping message nobody is waiting for: {line}");
+        }
+    }
+}
+
+impl NetworkState {
+    /// When each copy of a message from `src` to `dest` is delivered, if at all
+    fn schedule(&mut self, network: &Network, src: NodeId, dest: NodeId) -> Vec<Instant> {
+        let now = Instant::now();
+        let between_nodes = network.inboxes.contains_key(&src) && network.inboxes.contains_key(&dest);
+        let link = (src, dest);
+
+        if !between_nodes {
+            return vec![now + network.faults.latency.sample(&mut self.rng)];
+        }
+
+        let faults = self.link_faults.get(&link).unwrap_or(&network.faults).clone();
+
+        if self.partitioned.contains(&link) || self.rng.gen_bool(faults.drop_probability) {
+            debug!(src = link.0, dest = link.1, "Dropping message");
+            return Vec::new();
+        }
+
+        let copies = if self.rng.gen_bool(faults.duplicate_probability) { 2 } else { 1 };
+
+        (0..copies)
+            .map(|_| {
+                let deliver_at = now + faults.latency.sample(&mut self.rng);
+
+                if self.rng.gen_bool(faults.reorder_probability) {
+                    return deliver_at + faults.latency.sample(&mut self.rng);
+                }
+
+                let last_delivery = self.last_delivery.entry(link.clone()).or_insert(deliver_at);
+                *last_delivery = deliver_at.max(*last_delivery);
+                *last_delivery
+            })
+            .collect()
+    }
+}
+
+/// A node's end of the simulated network
+struct SimulatedTransport {
+    network: Arc<Network>,
+    inbox: UnboundedReceiver<String>,
+}
+
+impl Transport for SimulatedTransport {
+    async fn connect(self) -> Result<(Input, Output)> {
+        let input = futures::stream::unfold(self.inbox, |mut inbox| async move {
+            inbox.recv().await.map(|line| (Ok(line), inbox))
+        }).boxed();
+
+        let output = futures::sink::unfold(self.network, |network, line: String| async move {
+            network.route(line);
+            Ok::<_, Report>(network)
+        });
+
+        Ok((input, Box::pin(output)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_probabilities_are_ignored() {
+        let faults = LinkFaults::default()
+            .drop_probability(f64::NAN)
+            .duplicate_probability(f64::INFINITY)
+            .reorder_probability(2.0);
+
+        assert_eq!(faults.drop_probability, 0.0);
+        assert_eq!(faults.duplicate_probability, 0.0);
+        assert_eq!(faults.reorder_probability, 1.0);
+    }
+}
@@ -0,0 +1,329 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+use crate::NodeId;
+
+// Latencies are bucketed on a log scale, so percentiles are accurate to within about 4%
+const BUCKETS_PER_DOUBLING: f64 = 16.0;
+
+/// Counts messages by type and times client requests, the way Maelstrom grades the broadcast efficiency
+/// challenges. Messages count as server messages when they go from one node to another, and as client messages
+/// otherwise (clients and services alike).
+pub struct Stats {
+    servers: HashSet<NodeId>,
+    inner: Mutex<StatsInner>,
+}
+
+#[derive(Default)]
+struct StatsInner {
+    messages: BTreeMap<String, MessageCounts>,
+    latencies: BTreeMap<String, Histogram>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct MessageCounts {
+    pub server: u64,
+    pub client: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct StatsSummary {
+    pub server_messages: u64,
+    pub client_messages: u64,
+    /// Client requests that were answered
+    pub operations: u64,
+    /// Server messages per operation. Only a whole cluster's stats give the figure Maelstrom grades, a single
+    /// node's divide the messages it sent by the requests it answered itself.
+    pub msgs_per_op: f64,
+    pub messages: BTreeMap<String, MessageCounts>,
+    pub latency: LatencySummary,
+    pub latency_by_type: BTreeMap<String, LatencySummary>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct LatencySummary {
+    pub count: u64,
+    #[serde(serialize_with = "as_millis")]
+    pub p50: Duration,
+    #[serde(serialize_with = "as_millis")]
+    pub p95: Duration,
+    #[serde(serialize_with = "as_millis")]
+    pub p99: Duration,
+    #[serde(serialize_with = "as_millis")]
+    pub max: Duration,
+}
+
+#[derive(Clone, Default)]
+struct Histogram {
+    buckets: BTreeMap<u32, u64>,
+    count: u64,
+    max: Duration,
+}
+
+impl Stats {
+    pub fn new(servers: impl IntoIterator<Item = NodeId>) -> Self {
+        Self {
+            servers: servers.into_iter().collect(),
+            inner: Mutex::new(StatsInner::default()),
+        }
+    }
+
+    pub fn is_server(&self, node_id: &str) -> bool {
+        self.servers.contains(node_id)
+    }
+
+    pub fn record_message(&self, message: &Value) {
+        let src = message["src"].as_str().unwrap_or_default();
+        let dest = message["dest"].as_str().unwrap_or_default();
+        let message_type = message["body"]["type"].as_str().unwrap_or_default();
+
+        let mut inner = self.inner.lock().unwrap();
+        let counts = inner.messages.entry(message_type.to_string()).or_default();
+
+        match self.is_server(src) && self.is_server(dest) {
+            true => counts.server += 1,
+            false => counts.client += 1,
+        }
+    }
+
+    /// Records how long a client waited for the reply to a request of type `request_type`
+    pub fn record_latency(&self, request_type: &str, latency: Duration) {
+        self.inner.lock().unwrap().latencies
+            .entry(request_type.to_string())
+            .or_default()
+            .record(latency);
+    }
+
+    pub fn summary(&self) -> StatsSummary {
+        let inner = self.inner.lock().unwrap();
+
+        let server_messages = inner.messages.values().map(|counts| counts.server).sum::<u64>();
+        let client_messages = inner.messages.values().map(|counts| counts.client).sum::<u64>();
+
+        let overall = inner.latencies.values()
+            .fold(Histogram::default(), |mut overall, histogram| {
+                overall.merge(histogram);
+                overall
+            });
+
+        StatsSummary {
+            server_messages,
+            client_messages,
+            operations: overall.count,
+            msgs_per_op: match overall.count {
+                0 => 0.0,
+                operations => server_messages as f64 / operations as f64,
+            },
+            messages: inner.messages.clone(),
+            latency: overall.summary(),
+            latency_by_type: inner.latencies.iter()
+                .map(|(request_type, histogram)| (request_type.clone(), histogram.summary()))
+                .collect(),
+        }
+    }
+}
+
+impl Histogram {
+    fn record(&mut self, latency: Duration) {
+        let bucket = ((latency.as_micros() as f64 + 1.0).log2() * BUCKETS_PER_DOUBLING) as u32;
+
+        *self.buckets.entry(bucket).or_default() += 1;
+        self.count += 1;
+        self.max = self.max.max(latency);
+    }
+
+    fn merge(&mut self, other: &Histogram) {
+        for (bucket, count) in &other.buckets {
+            *self.buckets.entry(*bucket).or_default() += count;
+        }
+
+        self.count += other.count;
+        self.max = self.max.max(other.max);
+    }
+
+    /// The upper bound of the bucket holding the `quantile`th latency
+    fn percentile(&self, quantile: f64) -> Duration {
+        let rank = ((quantile * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+
+        for (bucket, count) in &self.buckets {
+            seen += count;
+
+            if seen >= rank {
+                let micros = ((*bucket + 1) as f64 / BUCKETS_PER_DOUBLING).exp2() - 1.0;
+                return Duration::from_secs_f64(micros / 1_000_000.0).min(self.max);
+            }
+        }
+
+        self.max
+    }
+
+    fn summary(&self) -> LatencySummary {
+        LatencySummary {
+            count: self.count,
+            p50: self.percentile(0.5),
+            p95: self.percentile(0.95),
+            p99: self.percentile(0.99),
+            max: self.max,
+        }
+    }
+}
+
+impl Display for StatsSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} server messages, {} client messages, {:.2} msgs/op over {} operations",
+            self.server_messages, self.client_messages, self.msgs_per_op, self.operations,
+        )?;
+
+        for (message_type, counts) in &self.messages {
+            write!(f, "\n  {message_type}: {} server, {} client", counts.server, counts.client)?;
+        }
+
+        write!(f, "\nLatency: {}", self.latency)?;
+
+        for (request_type, latency) in &self.latency_by_type {
+            write!(f, "\n  {request_type}: {latency}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for LatencySummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "p50 {:?}, p95 {:?}, p99 {:?}, max {:?}, count {}", self.p50, self.p95, self.p99, self.max, self.count)
+    }
+}
+
+fn as_millis<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64() * 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::{NodeServer, Reply, Router};
+    use crate::simulator::{NetworkConfig, Simulator};
+    use super::*;
+
+    /// How much larger a bucket's upper bound is than its lower one
+    fn bucket_width() -> f64 {
+        (1.0 / BUCKETS_PER_DOUBLING).exp2()
+    }
+
+    fn within_a_bucket(actual: Duration, expected: Duration) -> bool {
+        actual >= expected && actual.as_secs_f64() <= expected.as_secs_f64() * bucket_width()
+    }
+
+    #[test]
+    fn percentiles_are_within_a_bucket_of_the_latencies() {
+        let stats = Stats::new([]);
+
+        // Recorded out of order, 1ms to 100ms
+        for millis in (1..=100).rev() {
+            stats.record_latency("read", Duration::from_millis(millis));
+        }
+
+        let latency = stats.summary().latency_by_type["read"];
+
+        assert_eq!(latency.count, 100);
+        assert!(within_a_bucket(latency.p50, Duration::from_millis(50)), "p50 {:?}", latency.p50);
+        assert!(within_a_bucket(latency.p95, Duration::from_millis(95)), "p95 {:?}", latency.p95);
+        assert!(within_a_bucket(latency.p99, Duration::from_millis(99)), "p99 {:?}", latency.p99);
+        assert_eq!(latency.max, Duration::from_millis(100));
+    }
+
+    #[test]
+    fn every_latency_falls_below_its_buckets_upper_bound() {
+        for micros in [0, 1, 2, 7, 100, 1_023, 1_024, 65_537, 3_000_000] {
+            let latency = Duration::from_micros(micros);
+            let mut histogram = Histogram::default();
+
+            histogram.record(latency);
+            // A far larger latency keeps `max` from capping the bound
+            histogram.record(Duration::from_secs(3600));
+
+            let bound = histogram.percentile(0.5);
+            assert!(bound >= latency, "{latency:?} above its bucket's bound {bound:?}");
+            assert!(bound.as_secs_f64() <= (latency.as_secs_f64() + 1e-6) * bucket_width(), "{latency:?} has a bound of {bound:?}");
+        }
+    }
+
+    #[test]
+    fn merging_is_the_same_as_recording_together() {
+        let mut reads = Histogram::default();
+        let mut writes = Histogram::default();
+        let mut together = Histogram::default();
+
+        for millis in 1..=20 {
+            let latency = Duration::from_millis(millis * 7);
+            together.record(latency);
+
+            match millis % 3 {
+                0 => reads.record(latency),
+                _ => writes.record(latency),
+            }
+        }
+
+        reads.merge(&writes);
+
+        assert_eq!(reads.buckets, together.buckets);
+        assert_eq!(reads.count, together.count);
+        assert_eq!(reads.max, together.max);
+    }
+
+    #[test]
+    fn only_messages_between_servers_count_towards_msgs_per_op() {
+        let stats = Stats::new(["n0".to_string(), "n1".to_string()]);
+
+        for (src, dest, message_type) in [("n0", "n1", "sync"), ("n1", "n0", "sync_ok"), ("n0", "n1", "sync"), ("c1", "n0", "read"), ("n0", "c1", "read_ok"), ("n0", "seq-kv", "read")] {
+            stats.record_message(&json!({ "src": src, "dest": dest, "body": { "type": message_type } }));
+        }
+
+        stats.record_latency("read", Duration::from_millis(1));
+        stats.record_latency("broadcast", Duration::from_millis(1));
+
+        let summary = stats.summary();
+
+        assert_eq!(summary.server_messages, 3);
+        assert_eq!(summary.client_messages, 3);
+        assert_eq!(summary.messages["sync"], MessageCounts { server: 2, client: 0 });
+        assert_eq!(summary.messages["read"], MessageCounts { server: 0, client: 2 });
+        assert_eq!(summary.operations, 2);
+        assert_eq!(summary.msgs_per_op, 1.5);
+    }
+
+    async fn echo(message: crate::Message<serde_json::Value>) -> Reply<serde_json::Value> {
+        Reply(json!({ "type": "echo_ok", "echo": message.body.payload["echo"] }))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn each_node_writes_its_own_stats_file() {
+        let dir = std::env::temp_dir().join(format!("dsc-stats-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("stats-{node_id}.json");
+
+        let simulator = Simulator::start(2, NetworkConfig::default(), || {
+            NodeServer::new((), Router::new().route("echo", echo)).stats_file(path.clone())
+        }).await.unwrap();
+
+        let _: serde_json::Value = simulator.request("n1", json!({ "type": "echo", "echo": "hi" })).await.unwrap();
+        simulator.shutdown().await.unwrap();
+
+        for (node_id, echoes) in [("n0", 0), ("n1", 1)] {
+            let file = dir.join(format!("stats-{node_id}.json"));
+            let stats = serde_json::from_slice::<serde_json::Value>(&std::fs::read(&file).unwrap()).unwrap();
+
+            assert_eq!(stats["messages"]["echo_ok"]["client"].as_u64().unwrap_or(0), echoes, "{node_id}: {stats}");
+            assert_eq!(stats["server_messages"], 0);
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::str::FromStr;
+
+use color_eyre::eyre::{eyre, Report};
+use crate::NodeId;
+
+/// How broadcast nodes choose the neighbours they gossip with. Every strategy gives an undirected graph, so a node
+/// is a neighbour of each of its neighbours. Nodes are placed in the order of `node_ids`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TopologyStrategy {
+    /// The topology from Maelstrom's `topology` message, a grid by default
+    #[default]
+    Provided,
+    FullyConnected,
+    /// A tree spanning the provided topology, grown breadth-first from the first node
+    SpanningTree,
+    /// A tree in which every node has up to `arity` children, filled level by level
+    KaryTree {
+        arity: usize,
+    },
+    Ring,
+    /// `hubs` nodes connected to each other, with every other node attached to a single hub
+    HubAndSpoke {
+        hubs: usize,
+    },
+}
+
+impl TopologyStrategy {
+    /// The neighbours of `node_id`, given the topology Maelstrom provided
+    pub fn neighbours(&self, node_id: &str, node_ids: &[NodeId], provided: &HashMap<NodeId, Vec<NodeId>>) -> Vec<NodeId> {
+        self.topology(node_ids, provided)
+            .remove(node_id)
+            .unwrap_or_default()
+    }
+
+    pub fn topology(&self, node_ids: &[NodeId], provided: &HashMap<NodeId, Vec<NodeId>>) -> HashMap<NodeId, Vec<NodeId>> {
+        let mut edges = Vec::new();
+
+        match *self {
+            TopologyStrategy::Provided => {
+                for (node_id, neighbours) in provided {
+                    edges.extend(neighbours.iter().map(|neighbour| (node_id.clone(), neighbour.clone())));
+                }
+            },
+            TopologyStrategy::FullyConnected => {
+                for (index, node_id) in node_ids.iter().enumerate() {
+                    edges.extend(node_ids[index + 1..].iter().map(|other| (node_id.clone(), other.clone())));
+                }
+            },
+            TopologyStrategy::SpanningTree => edges = spanning_tree(node_ids, provided),
+            TopologyStrategy::KaryTree { arity } => {
+                let arity = arity.max(1);
+
+                for (index, node_id) in node_ids.iter().enumerate().skip(1) {
+                    edges.push((node_ids[(index - 1) / arity].clone(), node_id.clone()));
+                }
+            },
+            TopologyStrategy::Ring => {
+                for (index, node_id) in node_ids.iter().enumerate() {
+                    edges.push((node_id.clone(), node_ids[(index + 1) % node_ids.len()].clone()));
+                }
+            },
+            TopologyStrategy::HubAndSpoke { hubs } => {
+                let hubs = hubs.clamp(1, node_ids.len().max(1));
+
+                for (index, node_id) in node_ids.iter().enumerate() {
+                    match index < hubs {
+                        true => edges.extend(node_ids[index + 1..hubs].iter().map(|hub| (node_id.clone(), hub.clone()))),
+                        false => edges.push((node_ids[index % hubs].clone(), node_id.clone())),
+                    }
+                }
+            },
+        }
+
+        undirected(node_ids, edges)
+    }
+}
+
+impl FromStr for TopologyStrategy {
+    type Err = Report;
+
+    /// Parses `grid`, `full`, `spanning-tree`, `tree` or `tree:<arity>`, `ring` and `hub-and-spoke` or
+    /// `hub-and-spoke:<hubs>`
+    fn from_str(strategy: &str) -> Result<Self, Self::Err> {
+        let (name, size) = match strategy.split_once(':') {
+            Some((name, size)) => (name, Some(size.parse::<usize>().map_err(|err| eyre!("Invalid size in {strategy:?}: {err}"))?)),
+            None => (strategy, None),
+        };
+
+        match name {
+            "grid" | "provided" => Ok(TopologyStrategy::Provided),
+            "full" | "fully-connected" => Ok(TopologyStrategy::FullyConnected),
+            "spanning-tree" => Ok(TopologyStrategy::SpanningTree),
+            "tree" => Ok(TopologyStrategy::KaryTree { arity: size.unwrap_or(4) }),
+            "ring" => Ok(TopologyStrategy::Ring),
+            "hub-and-spoke" => Ok(TopologyStrategy::HubAndSpoke { hubs: size.unwrap_or(5) }),
+            _ => Err(eyre!("Unknown topology strategy {strategy:?}")),
+        }
+    }
+}
+
+/// Maelstrom's default broadcast topology: nodes laid out row by row in a square, each a neighbour of the nodes
+/// next to it
+pub fn grid(node_ids: &[NodeId]) -> HashMap<NodeId, Vec<NodeId>> {
+    let width = (node_ids.len() as f64).sqrt().ceil().max(1.0) as usize;
+    let mut edges = Vec::new();
+
+    for (index, node_id) in node_ids.iter().enumerate() {
+        if index % width + 1 < width {
+            edges.extend(node_ids.get(index + 1).map(|right| (node_id.clone(), right.clone())));
+        }
+
+        edges.extend(node_ids.get(index + width).map(|below| (node_id.clone(), below.clone())));
+    }
+
+    undirected(node_ids, edges)
+}
+
+fn spanning_tree(node_ids: &[NodeId], provided: &HashMap<NodeId, Vec<NodeId>>) -> Vec<(NodeId, NodeId)> {
+    let Some(root) = node_ids.first() else {
+        return Vec::new();
+    };
+
+    let mut edges = Vec::new();
+    let mut visited = HashSet::from([root]);
+    let mut queue = VecDeque::from([root]);
+
+    while let Some(node_id) = queue.pop_front() {
+        for neighbour in provided.get(node_id).into_iter().flatten() {
+            if visited.insert(neighbour) {
+                edges.push((node_id.clone(), neighbour.clone()));
+                queue.push_back(neighbour);
+            }
+        }
+    }
+
+    // Nodes the provided topology doesn't reach hang off the root so that nobody misses out
+    for node_id in node_ids.iter().filter(|node_id| !visited.contains(node_id)) {
+        edges.push((root.clone(), node_id.clone()));
+    }
+
+    edges
+}
+
+fn undirected(node_ids: &[NodeId], edges: Vec<(NodeId, NodeId)>) -> HashMap<NodeId, Vec<NodeId>> {
+    let mut topology = node_ids.iter()
+        .map(|node_id| (node_id.clone(), Vec::new()))
+        .collect::<HashMap<_, Vec<_>>>();
+
+    for (a, b) in edges.into_iter().filter(|(a, b)| a != b) {
+        for (node_id, neighbour) in [(&a, &b), (&b, &a)] {
+            let neighbours = topology.entry(node_id.clone()).or_default();
+
+            if !neighbours.contains(neighbour) {
+                neighbours.push(neighbour.clone());
+            }
+        }
+    }
+
+    topology
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node_ids(count: usize) -> Vec<NodeId> {
+        (0..count).map(|index| format!("n{index}")).collect()
+    }
+
+    fn sorted(topology: &HashMap<NodeId, Vec<NodeId>>, node_id: &str) -> Vec<String> {
+        let mut neighbours = topology[node_id].clone();
+        neighbours.sort();
+        neighbours
+    }
+
+    fn edge_count(topology: &HashMap<NodeId, Vec<NodeId>>) -> usize {
+        topology.values().map(Vec::len).sum::<usize>() / 2
+    }
+
+    #[test]
+    fn grid_rows_are_as_wide_as_the_square_root() {
+        let topology = grid(&node_ids(9));
+
+        assert_eq!(sorted(&topology, "n0"), ["n1", "n3"]);
+        assert_eq!(sorted(&topology, "n4"), ["n1", "n3", "n5", "n7"]);
+        // The end of a row doesn't wrap around to the start of the next
+        assert_eq!(sorted(&topology, "n2"), ["n1", "n5"]);
+
+        // Ten nodes need rows of four
+        let topology = grid(&node_ids(10));
+        assert_eq!(sorted(&topology, "n0"), ["n1", "n4"]);
+        assert_eq!(sorted(&topology, "n9"), ["n5", "n8"]);
+    }
+
+    #[test]
+    fn kary_tree_nodes_hang_off_their_parents() {
+        let topology = TopologyStrategy::KaryTree { arity: 2 }.topology(&node_ids(7), &HashMap::new());
+
+        assert_eq!(sorted(&topology, "n0"), ["n1", "n2"]);
+        assert_eq!(sorted(&topology, "n1"), ["n0", "n3", "n4"]);
+        assert_eq!(sorted(&topology, "n2"), ["n0", "n5", "n6"]);
+        assert_eq!(sorted(&topology, "n6"), ["n2"]);
+        assert_eq!(edge_count(&topology), 6);
+
+        // An arity of zero is a chain
+        let topology = TopologyStrategy::KaryTree { arity: 0 }.topology(&node_ids(3), &HashMap::new());
+        assert_eq!(sorted(&topology, "n1"), ["n0", "n2"]);
+    }
+
+    #[test]
+    fn hubs_connect_to_each_other_and_spokes_to_one_hub() {
+        let topology = TopologyStrategy::HubAndSpoke { hubs: 2 }.topology(&node_ids(6), &HashMap::new());
+
+        assert_eq!(sorted(&topology, "n0"), ["n1", "n2", "n4"]);
+        assert_eq!(sorted(&topology, "n1"), ["n0", "n3", "n5"]);
+
+        for spoke in ["n2", "n3", "n4", "n5"] {
+            assert_eq!(topology[spoke].len(), 1, "{spoke} has more than one hub");
+        }
+
+        // More hubs than nodes makes every node a hub
+        let topology = TopologyStrategy::HubAndSpoke { hubs: 5 }.topology(&node_ids(3), &HashMap::new());
+        assert_eq!(edge_count(&topology), 3);
+    }
+
+    #[test]
+    fn spanning_tree_reaches_nodes_the_provided_topology_does_not() {
+        let provided = HashMap::from([
+            ("n0".to_string(), vec!["n1".to_string(), "n2".to_string()]),
+            ("n1".to_string(), vec!["n0".to_string(), "n2".to_string()]),
+            ("n2".to_string(), vec!["n0".to_string(), "n1".to_string()]),
+        ]);
+
+        let topology = TopologyStrategy::SpanningTree.topology(&node_ids(4), &provided);
+
+        // The cycle between the first three is broken, and n3 is attached to the root
+        assert_eq!(sorted(&topology, "n0"), ["n1", "n2", "n3"]);
+        assert_eq!(sorted(&topology, "n3"), ["n0"]);
+        assert_eq!(edge_count(&topology), 3);
+    }
+
+    #[test]
+    fn ring_nodes_neighbour_the_ones_either_side() {
+        let topology = TopologyStrategy::Ring.topology(&node_ids(5), &HashMap::new());
+
+        assert_eq!(sorted(&topology, "n0"), ["n1", "n4"]);
+        assert_eq!(sorted(&topology, "n2"), ["n1", "n3"]);
+        assert_eq!(edge_count(&topology), 5);
+
+        // A single node is not its own neighbour
+        let topology = TopologyStrategy::Ring.topology(&node_ids(1), &HashMap::new());
+        assert!(topology["n0"].is_empty());
+    }
+
+    #[test]
+    fn parses_strategies() {
+        assert_eq!("grid".parse::<TopologyStrategy>().unwrap(), TopologyStrategy::Provided);
+        assert_eq!("full".parse::<TopologyStrategy>().unwrap(), TopologyStrategy::FullyConnected);
+        assert_eq!("spanning-tree".parse::<TopologyStrategy>().unwrap(), TopologyStrategy::SpanningTree);
+        assert_eq!("tree".parse::<TopologyStrategy>().unwrap(), TopologyStrategy::KaryTree { arity: 4 });
+        assert_eq!("tree:3".parse::<TopologyStrategy>().unwrap(), TopologyStrategy::KaryTree { arity: 3 });
+        assert_eq!("ring".parse::<TopologyStrategy>().unwrap(), TopologyStrategy::Ring);
+        assert_eq!("hub-and-spoke:2".parse::<TopologyStrategy>().unwrap(), TopologyStrategy::HubAndSpoke { hubs: 2 });
+
+        for strategy in ["tree:", "tree:two", "tree:-1", "hub-and-spoke:1.5", "star"] {
+            assert!(strategy.parse::<TopologyStrategy>().is_err(), "{strategy} parsed");
+        }
+    }
+}
@@ -0,0 +1,521 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::future::Future;
+use std::io;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, OptionExt};
+use color_eyre::{Report, Result};
+use futures::{Sink, SinkExt, StreamExt};
+use futures::stream::BoxStream;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter, WriteHalf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio_util::codec::{FramedRead, FramedWrite, LinesCodec};
+use tracing::warn;
+use crate::{ErrorCode, Init, MaelstromError, Message, MessageBody, NodeId};
+
+/// Incoming messages, one JSON message per line
+pub type Input = BoxStream<'static, Result<String>>;
+/// Where outgoing messages are sent, one JSON message per item. A node flushes it after each batch of messages.
+pub type Output = Pin<Box<dyn Sink<String, Error = Report> + Send>>;
+
+/// How a node exchanges messages with everyone else. The first message on the input must be Maelstrom's `init`.
+pub trait Transport: Send + 'static {
+    fn connect(self) -> impl Future<Output = Result<(Input, Output)>> + Send;
+}
+
+/// Maelstrom's protocol: messages come in on stdin and go out on stdout
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stdio;
+
+impl Transport for Stdio {
+    async fn connect(self) -> Result<(Input, Output)> {
+        let output = SinkExt::<String>::sink_map_err(FramedWrite::new(tokio::io::stdout(), LinesCodec::new()), Report::from);
+
+        Ok((stdin_lines(), Box::pin(output)))
+    }
+}
+
+/// Stdin split into lines. Tokio reads stdin with a blocking read that can't be cancelled, so a node that shuts down
+/// for any reason but EOF keeps its runtime waiting on that read until the next line or EOF arrives. Maelstrom
+/// closes stdin when it is done with a node, so in practice that only holds up a node stopped by a signal.
+fn stdin_lines() -> Input {
+    lines(tokio::io::stdin())
+}
+
+fn lines<R: AsyncRead + Send + 'static>(reader: R) -> Input {
+    FramedRead::new(reader, LinesCodec::new())
+        .map(|line| Ok(line?))
+        .boxed()
+}
+
+/// A kind of socket the nodes of a `SocketTransport` cluster listen on and connect to each other with
+pub trait Socket: Send + Sync + 'static {
+    type Address: Clone + Debug + Send + Sync + 'static;
+    type Stream: AsyncRead + AsyncWrite + Send + 'static;
+    type Listener: Send + 'static;
+
+    fn bind(address: &Self::Address) -> io::Result<Self::Listener>;
+    fn accept(listener: &Self::Listener) -> impl Future<Output = io::Result<Self::Stream>> + Send;
+    fn connect(address: &Self::Address) -> impl Future<Output = io::Result<Self::Stream>> + Send;
+}
+
+pub struct Tcp;
+
+impl Socket for Tcp {
+    type Address = SocketAddr;
+    type Stream = TcpStream;
+    type Listener = TcpListener;
+
+    fn bind(address: &SocketAddr) -> io::Result<TcpListener> {
+        let listener = std::net::TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        TcpListener::from_std(listener)
+    }
+
+    async fn accept(listener: &TcpListener) -> io::Result<TcpStream> {
+        let (stream, _) = listener.accept().await?;
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+
+    async fn connect(address: &SocketAddr) -> io::Result<TcpStream> {
+        let stream = TcpStream::connect(address).await?;
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+}
+
+pub struct Unix;
+
+impl Socket for Unix {
+    type Address = PathBuf;
+    type Stream = UnixStream;
+    type Listener = UnixListener;
+
+    fn bind(address: &PathBuf) -> io::Result<UnixListener> {
+        // A socket file left behind by an earlier run would make the bind fail
+        let _ = std::fs::remove_file(address);
+        UnixListener::bind(address)
+    }
+
+    async fn accept(listener: &UnixListener) -> io::Result<UnixStream> {
+        Ok(listener.accept().await?.0)
+    }
+
+    async fn connect(address: &PathBuf) -> io::Result<UnixStream> {
+        UnixStream::connect(address).await
+    }
+}
+
+pub type TcpTransport = SocketTransport<Tcp>;
+pub type UnixTransport = SocketTransport<Unix>;
+
+/// Runs a node as one process of a cluster whose nodes talk to each other directly over sockets, without
+/// Maelstrom. Every node knows the address of every other by its `NodeId`, and `init` is generated from them.
+///
+/// Clients connect to any node and send it messages like Maelstrom would. Replies to a client go back over the
+/// connection its messages came in on.
+pub struct SocketTransport<K: Socket> {
+    node_id: NodeId,
+    addresses: HashMap<NodeId, K::Address>,
+    connect_timeout: Duration,
+    _socket: PhantomData<K>,
+}
+
+impl<K: Socket> SocketTransport<K> {
+    /// `addresses` has to include the node's own address, which it listens on
+    pub fn new(node_id: impl Into<NodeId>, addresses: HashMap<NodeId, K::Address>) -> Self {
+        Self {
+            node_id: node_id.into(),
+            addresses,
+            connect_timeout: Duration::from_secs(5),
+            _socket: PhantomData,
+        }
+    }
+
+    /// How long to keep retrying to connect to a peer that isn't listening yet. Messages to it are dropped after.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+}
+
+impl<K: Socket> Transport for SocketTransport<K> {
+    async fn connect(self) -> Result<(Input, Output)> {
+        let address = self.addresses.get(&self.node_id)
+            .ok_or_eyre("The node's own address is missing")?;
+        let listener = K::bind(address)?;
+
+        let mut node_ids = self.addresses.keys().cloned().collect::<Vec<_>>();
+        node_ids.sort();
+
+        let init = Message {
+            src: "c0".to_string(),
+            dest: self.node_id.clone(),
+            body: MessageBody {
+                message_id: Some(0),
+                in_reply_to: None,
+                payload: Init {
+                    node_id: self.node_id.clone(),
+                    node_ids,
+                },
+            },
+        };
+
+        let (input_tx, input_rx) = tokio::sync::mpsc::unbounded_channel();
+        input_tx.send(serde_json::to_string(&init)?)?;
+
+        let peers = Arc::new(Peers::<K> {
+            addresses: self.addresses,
+            connect_timeout: self.connect_timeout,
+            routes: Mutex::new(HashMap::new()),
+            input_tx,
+        });
+
+        {
+            let peers = peers.clone();
+            tokio::spawn(async move {
+                loop {
+                    match K::accept(&listener).await {
+                        Ok(stream) => peers.open(stream, None),
+                        Err(err) => warn!("Failed to accept connection: {err}"),
+                    }
+                }
+            });
+        }
+
+        let input = futures::stream::unfold(input_rx, |mut rx| async move {
+            rx.recv().await.map(|line| (Ok(line), rx))
+        }).boxed();
+
+        let output = futures::sink::unfold(peers, |peers, line: String| async move {
+            peers.route(line);
+            Ok::<_, Report>(peers)
+        });
+
+        Ok((input, Box::pin(output)))
+    }
+}
+
+struct Peers<K: Socket> {
+    addresses: HashMap<NodeId, K::Address>,
+    connect_timeout: Duration,
+    // Where messages to each node or client are queued for the connection that writes them
+    routes: Mutex<HashMap<NodeId, UnboundedSender<String>>>,
+    input_tx: UnboundedSender<String>,
+}
+
+impl<K: Socket> Peers<K> {
+    fn route(self: &Arc<Self>, line: String) {
+        let dest = match serde_json::from_str::<serde_json::Value>(&line) {
+            Ok(value) => value["dest"].as_str().unwrap_or_default().to_string(),
+            Err(err) => {
+                warn!("Dropping malformed message {line:?}: {err}");
+                return;
+            },
+        };
+
+        let mut routes = self.routes.lock().unwrap();
+
+        if let Some(route) = routes.get(&dest) {
+            if route.send(line.clone()).is_ok() {
+                return;
+            }
+
+            routes.remove(&dest);
+        }
+
+        let Some(address) = self.addresses.get(&dest).cloned() else {
+            drop(routes);
+            self.reject(&line, &dest);
+            return;
+        };
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let _ = tx.send(line);
+        routes.insert(dest.clone(), tx);
+
+        let peers = self.clone();
+        tokio::spawn(async move {
+            match peers.dial(&address).await {
+                Ok(stream) => peers.open(stream, Some(rx)),
+                Err(err) => {
+                    warn!("Failed to connect to {dest} at {address:?}, dropping its messages: {err:#}");
+                    peers.routes.lock().unwrap().remove(&dest);
+                },
+            }
+        });
+    }
+
+    /// Answers a request to a destination without an address, which includes Maelstrom's services since there is
+    /// no Maelstrom to provide them, with a `node-not-found` error rather than leaving it to time out
+    fn reject(&self, line: &str, dest: &str) {
+        warn!("No address for {dest}, rejecting message");
+
+        let Ok(request) = serde_json::from_str::<Message<serde_json::Value>>(line) else {
+            return;
+        };
+
+        // Replies, e.g. to a client that has gone away, are only dropped
+        let (Some(message_id), None) = (request.body.message_id, request.body.in_reply_to) else {
+            return;
+        };
+
+        let error = Message {
+            src: request.dest,
+            dest: request.src,
+            body: MessageBody {
+                message_id: None,
+                in_reply_to: Some(message_id),
+                payload: MaelstromError::new(ErrorCode::NodeNotFound, format!("{dest} is not reachable over sockets")),
+            },
+        };
+
+        if let Ok(error) = serde_json::to_string(&error) {
+            let _ = self.input_tx.send(error);
+        }
+    }
+
+    async fn dial(&self, address: &K::Address) -> Result<K::Stream> {
+        let connect = async {
+            loop {
+                match K::connect(address).await {
+                    Ok(stream) => return stream,
+                    Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+                }
+            }
+        };
+
+        tokio::time::timeout(self.connect_timeout, connect)
+            .await
+            .map_err(|_| eyre!("Timed out after {:?}", self.connect_timeout))
+    }
+
+    /// Starts reading and writing messages on a connection. Messages for a connection we dialled are already
+    /// queued on `outgoing`, while an accepted connection gets a route once we know who is on the other end.
+    fn open(self: &Arc<Self>, stream: K::Stream, outgoing: Option<UnboundedReceiver<String>>) {
+        let (read, write) = tokio::io::split(stream);
+
+        let (reply_route, outgoing) = match outgoing {
+            Some(outgoing) => (None, outgoing),
+            None => {
+                let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+                (Some(tx), rx)
+            },
+        };
+
+        tokio::spawn(self.clone().read_connection(lines(read), reply_route));
+        tokio::spawn(write_connection(write, outgoing));
+    }
+
+    async fn read_connection(self: Arc<Self>, mut lines: Input, mut reply_route: Option<UnboundedSender<String>>) {
+        let mut routed = None;
+
+        while let Some(Ok(line)) = lines.next().await {
+            if let Some(tx) = reply_route.take() {
+                let src = serde_json::from_str::<serde_json::Value>(&line)
+                    .ok()
+                    .and_then(|value| value["src"].as_str().map(str::to_string));
+
+                match src {
+                    // The newest connection wins, so a client that reconnects gets its replies on the new one
+                    Some(src) => {
+                        self.routes.lock().unwrap().insert(src.clone(), tx.clone());
+                        routed = Some((src, tx));
+                    },
+                    None => reply_route = Some(tx),
+                }
+            }
+
+            if self.input_tx.send(line).is_err() {
+                break;
+            }
+        }
+
+        // Forget the route once the connection is gone, unless a newer connection has taken it over already
+        if let Some((src, tx)) = routed {
+            let mut routes = self.routes.lock().unwrap();
+
+            if routes.get(&src).is_some_and(|route| route.same_channel(&tx)) {
+                routes.remove(&src);
+            }
+        }
+    }
+}
+
+async fn write_connection<S: AsyncWrite + Send>(write: WriteHalf<S>, mut outgoing: UnboundedReceiver<String>) {
+    let mut write = BufWriter::new(write);
+    let mut batch = Vec::new();
+
+    while outgoing.recv_many(&mut batch, 256).await > 0 {
+        for line in batch.drain(..) {
+            if write.write_all(line.as_bytes()).await.is_err() || write.write_all(b"\n").await.is_err() {
+                return;
+            }
+        }
+
+        if write.flush().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Picks the transport from the environment. Nodes use `Stdio` unless `DSC_TRANSPORT` is `tcp` or `unix`, in which
+/// case `DSC_NODE_ID` names the node and `DSC_NODE_ADDRESSES` lists every node as `id=address`, separated by commas,
+/// where the addresses are `host:port` for `tcp` and socket paths for `unix`.
+pub(crate) async fn connect_from_env() -> Result<(Input, Output)> {
+    let transport = std::env::var("DSC_TRANSPORT").unwrap_or_default();
+
+    if matches!(transport.as_str(), "" | "stdio") {
+        return Stdio.connect().await;
+    }
+
+    let node_id = std::env::var("DSC_NODE_ID").map_err(|_| eyre!("DSC_TRANSPORT={transport} needs DSC_NODE_ID"))?;
+    let addresses = std::env::var("DSC_NODE_ADDRESSES").map_err(|_| eyre!("DSC_TRANSPORT={transport} needs DSC_NODE_ADDRESSES"))?;
+
+    let addresses = addresses.split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            let (id, address) = entry.split_once('=').ok_or_else(|| eyre!("Expected id=address, got {entry:?}"))?;
+            Ok((id.trim().to_string(), address.trim().to_string()))
+        })
+        .collect::<Result<HashMap<_, _>>>()?;
+
+    match transport.as_str() {
+        "tcp" => {
+            let addresses = addresses.into_iter()
+                .map(|(id, address)| Ok((id, address.parse().map_err(|err| eyre!("Invalid address {address:?}: {err}"))?)))
+                .collect::<Result<_>>()?;
+
+            TcpTransport::new(node_id, addresses).connect().await
+        },
+        "unix" => {
+            let addresses = addresses.into_iter()
+                .map(|(id, address)| (id, PathBuf::from(address)))
+                .collect();
+
+            UnixTransport::new(node_id, addresses).connect().await
+        },
+        _ => Err(eyre!("Unknown DSC_TRANSPORT {transport:?}, expected stdio, tcp or unix")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use super::*;
+
+    fn peers() -> (Arc<Peers<Tcp>>, UnboundedReceiver<String>) {
+        let (input_tx, input_rx) = tokio::sync::mpsc::unbounded_channel();
+
+        let peers = Arc::new(Peers {
+            addresses: HashMap::from([("n0".to_string(), "127.0.0.1:1".parse().unwrap())]),
+            connect_timeout: Duration::from_secs(1),
+            routes: Mutex::new(HashMap::new()),
+            input_tx,
+        });
+
+        (peers, input_rx)
+    }
+
+    #[tokio::test]
+    async fn requests_to_services_fail_straight_away() {
+        let (peers, mut input) = peers();
+
+        peers.route(json!({ "src": "n0", "dest": "seq-kv", "body": { "type": "read", "key": "a", "msg_id": 3 } }).to_string());
+
+        let error = serde_json::from_str::<Value>(&input.try_recv().unwrap()).unwrap();
+        assert_eq!(error["src"], "seq-kv");
+        assert_eq!(error["dest"], "n0");
+        assert_eq!(error["body"]["type"], "error");
+        assert_eq!(error["body"]["code"], 1);
+        assert_eq!(error["body"]["in_reply_to"], 3);
+    }
+
+    #[tokio::test]
+    async fn replies_to_unknown_destinations_are_dropped() {
+        let (peers, mut input) = peers();
+
+        peers.route(json!({ "src": "n0", "dest": "c1", "body": { "type": "echo_ok", "msg_id": 4, "in_reply_to": 1 } }).to_string());
+
+        assert!(input.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn skips_blank_lines_and_stops_at_eof() {
+        let init = json!({ "src": "c0", "dest": "n0", "body": { "type": "init", "msg_id": 0, "node_id": "n0", "node_ids": ["n0"] } });
+        let echo = json!({ "src": "c1", "dest": "n0", "body": { "type": "echo", "echo": "hi", "msg_id": 1 } });
+        let text = format!("\n  \n{init}\n\n\t\n{echo}\n\n\n");
+
+        let (output_tx, mut output_rx) = tokio::sync::mpsc::unbounded_channel();
+        let output = futures::sink::unfold(output_tx, |tx, line: String| async move {
+            tx.send(line)?;
+            Ok::<_, Report>(tx)
+        });
+
+        let server = crate::challenges::echo::server();
+        server.serve_on(lines(std::io::Cursor::new(text.into_bytes())), Box::pin(output), Default::default()).await.unwrap();
+
+        let replies = std::iter::from_fn(|| output_rx.try_recv().ok())
+            .map(|line| serde_json::from_str::<Value>(&line).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0]["body"]["type"], "init_ok");
+        assert_eq!(replies[1]["body"]["type"], "echo_ok");
+        assert_eq!(replies[1]["body"]["echo"], "hi");
+    }
+
+    async fn recv_json(input: &mut Input) -> Value {
+        let line = tokio::time::timeout(Duration::from_secs(5), input.next()).await.unwrap().unwrap().unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    async fn read_json<R: AsyncRead + Unpin>(lines: &mut FramedRead<R, LinesCodec>) -> Value {
+        let line = tokio::time::timeout(Duration::from_secs(5), lines.next()).await.unwrap().unwrap().unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[tokio::test]
+    async fn replies_go_back_over_the_clients_latest_connection() {
+        let address: SocketAddr = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+
+        let transport = TcpTransport::new("n0", HashMap::from([("n0".to_string(), address)]));
+        let (mut input, mut output) = transport.connect().await.unwrap();
+        assert_eq!(recv_json(&mut input).await["body"]["type"], "init");
+
+        for message_id in 1..=2 {
+            let stream = TcpStream::connect(address).await.unwrap();
+            let (read, mut write) = tokio::io::split(stream);
+            let mut replies = FramedRead::new(read, LinesCodec::new());
+
+            let request = json!({ "src": "c1", "dest": "n0", "body": { "type": "read", "msg_id": message_id } });
+            write.write_all(format!("{request}\n").as_bytes()).await.unwrap();
+
+            let request = recv_json(&mut input).await;
+            assert_eq!(request["src"], "c1");
+            assert_eq!(request["body"]["msg_id"], message_id);
+
+            let reply = json!({ "src": "n0", "dest": "c1", "body": { "type": "read_ok", "in_reply_to": message_id } });
+            output.send(reply.to_string()).await.unwrap();
+
+            let reply = read_json(&mut replies).await;
+            assert_eq!(reply["body"]["type"], "read_ok");
+            assert_eq!(reply["body"]["in_reply_to"], message_id);
+
+            // Closing the connection before the client comes back on a new one
+            drop(write);
+            drop(replies);
+        }
+    }
+}
@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use color_eyre::eyre::bail;
+use color_eyre::Result;
+use futures::future::join_all;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use serde_json::{json, Value};
+use tokio::time::{Instant, MissedTickBehavior};
+use tracing::info;
+use crate::checker::{self, Checker};
+use crate::{mix_seed, NodeId};
+use crate::simulator::{Simulator, DEFAULT_CLIENT_ID};
+use crate::topology::grid;
+
+/// The request mixes of Maelstrom's workloads. Running one against a `Simulator` leaves its operations in the
+/// simulator's history, ready for `Workload::checker`.
+#[derive(Clone, Debug)]
+pub enum Workload {
+    Echo,
+    UniqueIds,
+    /// Sends `topology` to every node first, Maelstrom's grid if not given
+    Broadcast {
+        topology: Option<HashMap<NodeId, Vec<NodeId>>>,
+    },
+    GCounter,
+    Kafka,
+    LinKv,
+    /// Transactions of reads and writes over integer registers, as in Maelstrom's `txn-rw-register`
+    TxnRwRegister {
+        max_txn_length: usize,
+    },
+}
+
+/// The shortest time between two requests of the same client
+const MIN_PERIOD: Duration = Duration::from_micros(1);
+
+#[derive(Clone, Debug)]
+pub struct WorkloadConfig {
+    seed: u64,
+    rate: f64,
+    concurrency: usize,
+    duration: Duration,
+    keys: KeyDistribution,
+    recovery: Duration,
+}
+
+impl WorkloadConfig {
+    /// Seeds the choice of nodes, keys and values
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Requests per second, across all clients. `Workload::run` fails unless it is finite and positive.
+    pub fn rate(mut self, rate: f64) -> Self {
+        self.rate = rate;
+        self
+    }
+
+    /// How many clients send requests at once. Each waits for a reply before sending its next request.
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    pub fn keys(mut self, keys: KeyDistribution) -> Self {
+        self.keys = keys;
+        self
+    }
+
+    /// How long to wait after the last request before the final reads of `broadcast` and `g-counter`
+    pub fn recovery(mut self, recovery: Duration) -> Self {
+        self.recovery = recovery;
+        self
+    }
+
+    /// How often each client sends a request, so that all of them together keep to `rate`
+    fn period(&self) -> Duration {
+        let period = Duration::try_from_secs_f64(self.concurrency as f64 / self.rate).unwrap_or(Duration::MAX);
+        period.clamp(MIN_PERIOD, self.duration.max(MIN_PERIOD))
+    }
+}
+
+impl Default for WorkloadConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            rate: 10.0,
+            concurrency: 2,
+            duration: Duration::from_secs(10),
+            keys: KeyDistribution::Uniform {
+                keys: 5,
+            },
+            recovery: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Which keys the `kafka`, `lin-kv` and `txn-rw-register` workloads pick
+#[derive(Clone, Debug)]
+pub enum KeyDistribution {
+    Uniform {
+        keys: usize,
+    },
+    /// Key `k` is picked with a probability proportional to `1 / (k + 1)^exponent`, so a few keys are hot
+    Zipf {
+        keys: usize,
+        exponent: f64,
+    },
+}
+
+impl KeyDistribution {
+    fn sample(&self, rng: &mut StdRng) -> u64 {
+        match *self {
+            KeyDistribution::Uniform { keys } => rng.gen_range(0..keys.max(1)) as u64,
+            KeyDistribution::Zipf { keys, exponent } => {
+                let weight = |key: usize| 1.0 / ((key + 1) as f64).powf(exponent);
+                let mut remaining = rng.gen::<f64>() * (0..keys).map(weight).sum::<f64>();
+
+                for key in 0..keys {
+                    remaining -= weight(key);
+
+                    if remaining <= 0.0 {
+                        return key as u64;
+                    }
+                }
+
+                keys.saturating_sub(1) as u64
+            },
+        }
+    }
+}
+
+impl Workload {
+    /// The Maelstrom name of the workload, e.g. `g-counter`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Workload::Echo => "echo",
+            Workload::UniqueIds => "unique-ids",
+            Workload::Broadcast { .. } => "broadcast",
+            Workload::GCounter => "g-counter",
+            Workload::Kafka => "kafka",
+            Workload::LinKv => "lin-kv",
+            Workload::TxnRwRegister { .. } => "txn-rw-register",
+        }
+    }
+
+    /// The checker for the histories this workload produces, if there is one
+    pub fn checker(&self) -> Option<Box<dyn Checker>> {
+        checker::for_workload(self.name())
+    }
+
+    /// Sends requests from `config.concurrency` clients named `c1`, `c2`, ... to random nodes until
+    /// `config.duration` has passed, followed by the final reads of workloads that have them
+    pub async fn run(&self, simulator: &Simulator, config: &WorkloadConfig) -> Result<()> {
+        if !config.rate.is_finite() || config.rate <= 0.0 {
+            bail!("Workload rate must be finite and positive, got {}", config.rate);
+        }
+
+        let node_ids = simulator.node_ids();
+
+        if let Workload::Broadcast { topology } = self {
+            let topology = topology.clone().unwrap_or_else(|| grid(node_ids));
+
+            for node_id in node_ids {
+                let _: Value = simulator.request(node_id, json!({ "type": "topology", "topology": topology })).await?;
+            }
+        }
+
+        info!(workload = self.name(), rate = config.rate, concurrency = config.concurrency, "Running workload");
+
+        let deadline = Instant::now() + config.duration;
+        let values = AtomicU64::new(0);
+
+        let clients = (0..config.concurrency).map(|index| {
+            let client_id = format!("c{}", index + 1);
+            let mut rng = StdRng::seed_from_u64(mix_seed(config.seed, &client_id));
+            let mut generator = Generator::new(self, &config.keys, &values);
+
+            async move {
+                let mut interval = tokio::time::interval(config.period());
+                interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+                while interval.tick().await < deadline {
+                    let node_id = &node_ids[rng.gen_range(0..node_ids.len())];
+                    let request = generator.next(&mut rng);
+
+                    // Failed requests are in the history as they are, so there's nothing more to do about them
+                    if let Ok(reply) = simulator.request_from::<_, Value>(&client_id, node_id, &request).await {
+                        generator.observe(&reply);
+                    }
+                }
+            }
+        });
+
+        join_all(clients).await;
+
+        if matches!(self, Workload::Broadcast { .. } | Workload::GCounter) {
+            tokio::time::sleep(config.recovery).await;
+
+            for node_id in node_ids {
+                let _ = simulator.request_from::<_, Value>(DEFAULT_CLIENT_ID, node_id, json!({ "type": "read" })).await;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The requests of a single client
+struct Generator<'a> {
+    workload: &'a Workload,
+    keys: &'a KeyDistribution,
+    // Shared between clients so that no value is ever sent twice
+    values: &'a AtomicU64,
+    // Where the client's next kafka poll starts, by key
+    offsets: HashMap<String, u64>,
+}
+
+impl<'a> Generator<'a> {
+    fn new(workload: &'a Workload, keys: &'a KeyDistribution, values: &'a AtomicU64) -> Self {
+        Self {
+            workload,
+            keys,
+            values,
+            offsets: HashMap::new(),
+        }
+    }
+
+    fn next(&mut self, rng: &mut StdRng) -> Value {
+        match self.workload {
+            Workload::Echo => json!({ "type": "echo", "echo": format!("Please echo {}", rng.gen_range(0..128)) }),
+            Workload::UniqueIds => json!({ "type": "generate" }),
+            Workload::Broadcast { .. } => match rng.gen_bool(0.5) {
+                true => json!({ "type": "broadcast", "message": self.value() }),
+                false => json!({ "type": "read" }),
+            },
+            Workload::GCounter => match rng.gen_bool(0.5) {
+                true => json!({ "type": "add", "delta": rng.gen_range(0..5) }),
+                false => json!({ "type": "read" }),
+            },
+            Workload::Kafka => match rng.gen_range(0..10) {
+                0..5 => json!({ "type": "send", "key": self.keys.sample(rng).to_string(), "msg": self.value() }),
+                5..7 => {
+                    let key = self.keys.sample(rng).to_string();
+                    let offset = self.offsets.get(&key).copied().unwrap_or(0);
+                    json!({ "type": "poll", "offsets": { key: offset } })
+                },
+                7..9 if !self.offsets.is_empty() => json!({ "type": "commit_offsets", "offsets": self.offsets }),
+                _ => json!({ "type": "list_committed_offsets", "keys": [self.keys.sample(rng).to_string()] }),
+            },
+            Workload::LinKv => {
+                let key = self.keys.sample(rng);
+
+                match rng.gen_range(0..3) {
+                    0 => json!({ "type": "read", "key": key }),
+                    1 => json!({ "type": "write", "key": key, "value": rng.gen_range(0..5) }),
+                    _ => json!({ "type": "cas", "key": key, "from": rng.gen_range(0..5), "to": rng.gen_range(0..5) }),
+                }
+            },
+            Workload::TxnRwRegister { max_txn_length } => {
+                let txn = (0..rng.gen_range(1..=(*max_txn_length).max(1)))
+                    .map(|_| match rng.gen_bool(0.5) {
+                        true => json!(["r", self.keys.sample(rng), null]),
+                        false => json!(["w", self.keys.sample(rng), self.value()]),
+                    })
+                    .collect::<Vec<_>>();
+
+                json!({ "type": "txn", "txn": txn })
+            },
+        }
+    }
+
+    /// Moves kafka polls past what the client has already seen
+    fn observe(&mut self, reply: &Value) {
+        for (key, entries) in reply["msgs"].as_object().into_iter().flatten() {
+            if let Some(offset) = entries.as_array().and_then(|entries| entries.last()).and_then(|entry| entry[0].as_u64()) {
+                self.offsets.insert(key.clone(), offset + 1);
+            }
+        }
+    }
+
+    fn value(&self) -> u64 {
+        self.values.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::challenges::echo;
+    use crate::simulator::NetworkConfig;
+    use super::*;
+
+    #[test]
+    fn client_periods_stay_within_the_run() {
+        let config = WorkloadConfig::default()
+            .concurrency(2)
+            .duration(Duration::from_secs(10));
+
+        assert_eq!(config.clone().rate(4.0).period(), Duration::from_millis(500));
+        assert_eq!(config.clone().rate(1e-300).period(), Duration::from_secs(10));
+        assert_eq!(config.clone().rate(1e300).period(), MIN_PERIOD);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn invalid_rates_are_rejected() {
+        let simulator = Simulator::start(1, NetworkConfig::default(), echo::server).await.unwrap();
+
+        for rate in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let config = WorkloadConfig::default().rate(rate);
+            assert!(Workload::Echo.run(&simulator, &config).await.is_err(), "accepted rate {rate}");
+        }
+
+        simulator.shutdown().await.unwrap();
+    }
+}